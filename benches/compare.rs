@@ -0,0 +1,53 @@
+use std::ops::Range;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dds::{compare::dirty_chunks, MIN_BLOCK_SIZE};
+
+/// How a block used to be compared, as a whole and then again chunk by chunk when it differed.
+///
+/// Kept here as the baseline the single pass comparer is measured against.
+fn compare_twice(input: &[u8], output: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    if input == output {
+        return Vec::new();
+    }
+    (0..input.len())
+        .step_by(chunk_size)
+        .map(|start| start..(start + chunk_size).min(input.len()))
+        .filter(|chunk| input[chunk.clone()] != output[chunk.clone()])
+        .collect()
+}
+
+fn bench_compare(c: &mut Criterion) {
+    const SIZE: usize = 1024 * 1024;
+    let output = vec![0u8; SIZE];
+
+    let mut fully_different = vec![0u8; SIZE];
+    // the last byte of every chunk, so each one is compared to the end before it's found dirty
+    for chunk in fully_different.chunks_mut(MIN_BLOCK_SIZE) {
+        *chunk.last_mut().unwrap() = 1;
+    }
+    let mut sparse = vec![0u8; SIZE];
+    for chunk in sparse.chunks_mut(MIN_BLOCK_SIZE).step_by(64) {
+        *chunk.last_mut().unwrap() = 1;
+    }
+    let cases = [
+        ("identical", output.clone()),
+        ("fully_different", fully_different),
+        ("sparse_diff", sparse),
+    ];
+
+    let mut group = c.benchmark_group("compare");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    for (name, input) in &cases {
+        group.bench_with_input(BenchmarkId::new("twice", name), input, |b, input| {
+            b.iter(|| compare_twice(black_box(input), black_box(&output), MIN_BLOCK_SIZE))
+        });
+        group.bench_with_input(BenchmarkId::new("single_pass", name), input, |b, input| {
+            b.iter(|| dirty_chunks(black_box(input), black_box(&output), SIZE, MIN_BLOCK_SIZE))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compare);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+use std::ops::Range;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dds::{pool::BufferPool, utils::WriteJob, BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+/// How `break_into_blocks` used to work, draining every clean chunk out of a copy of the input.
+///
+/// Kept here as the baseline the pooled version is measured against.
+fn drain_into_blocks(
+    mut input: Vec<u8>,
+    invalid: &[u8],
+    mut limit: usize,
+    min_block_size: usize,
+) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let (mut write_offset, mut start, mut end) = (0, 0, min_block_size);
+    loop {
+        end = end.min(limit);
+        if start >= end {
+            break;
+        }
+        let step_size = end - start;
+        if input[start..end] != invalid[write_offset..write_offset + step_size] {
+            blocks.push(start..end);
+            start += step_size;
+            end += step_size;
+        } else {
+            input.drain(start..end);
+            limit -= step_size;
+        }
+        write_offset += step_size;
+    }
+    blocks
+}
+
+/// A block of the output, and the input with every `every`th chunk changed.
+fn buffers(size: usize, every: usize) -> (Vec<u8>, Vec<u8>) {
+    let output = vec![0u8; size];
+    let mut input = output.clone();
+    for chunk in input.chunks_mut(MIN_BLOCK_SIZE).step_by(every) {
+        chunk[0] = 1;
+    }
+    (input, output)
+}
+
+fn bench_break_into_blocks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("break_into_blocks");
+    for size in [BLOCK_SIZE, 1024 * 1024] {
+        // a single differing chunk is the common case when restoring a card
+        let (input, output) = buffers(size, usize::MAX);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("drain", size), &size, |b, &size| {
+            b.iter(|| drain_into_blocks(input.clone(), &output, size, MIN_BLOCK_SIZE))
+        });
+
+        let pool = BufferPool::new(size);
+        group.bench_with_input(BenchmarkId::new("pooled", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut buffer = pool.take();
+                buffer.copy_from_slice(&input);
+                black_box(WriteJob::break_into_blocks(
+                    buffer,
+                    &output,
+                    size,
+                    0,
+                    MIN_BLOCK_SIZE,
+                ))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_break_into_blocks);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+use std::fs::{File, OpenOptions};
+
+use crate::{device, error::DdsError, input::Input, notice, Dds};
+
+/// Create the image if it's missing, so it can be opened and locked like any other output.
+pub fn create_image(cfg: &Dds) -> Result<(), DdsError> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(cfg.output())
+        .map_err(|e| DdsError::io("creating", cfg.output(), None, e))?;
+    Ok(())
+}
+
+/// Get the image ready to be refreshed from the device, through the locked handle to it.
+///
+/// The image is resized to match the device. New space is left as a hole, so only the parts of
+/// the device that aren't zero are ever written to the image. An image larger than the device is
+/// only shrunk with --allow-truncate, as that throws away its end.
+pub fn resize_image(cfg: &Dds, o_file: &File) -> Result<(), DdsError> {
+    let i_file_size = Input::open(&cfg.input, false)?.size().ok_or_else(|| {
+        DdsError::InvalidConfig("--backup can't be used with a compressed input".to_string())
+    })?;
+
+    // the image is a block device, so there is nothing to resize
+    if device::is_block_device(o_file)
+        .map_err(|e| DdsError::io("reading metadata of", cfg.output(), None, e))?
+    {
+        return Ok(());
+    }
+
+    let o_file_size = o_file
+        .metadata()
+        .map_err(|e| DdsError::io("reading metadata of", cfg.output(), None, e))?
+        .len();
+    if o_file_size == i_file_size {
+        return Ok(());
+    }
+
+    if o_file_size == 0 {
+        notice!("Creating image {} ({} bytes)", cfg.output(), i_file_size);
+    } else if o_file_size > i_file_size && !cfg.allow_truncate {
+        return Err(DdsError::SizeMismatch {
+            input: i_file_size,
+            output: o_file_size,
+        });
+    } else {
+        notice!(
+            "Resizing image {} from {} to {} bytes",
+            cfg.output(),
+            o_file_size,
+            i_file_size
+        );
+    }
+    o_file
+        .set_len(i_file_size)
+        .map_err(|e| DdsError::io("resizing", cfg.output(), Some(i_file_size), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_image, resize_image};
+    use crate::{error::DdsError, lock::open_output, Dds};
+
+    #[test]
+    fn test_resize_image() {
+        let input = "test_resize_image.bin";
+        let output = "test_resize_image.bin.img";
+        std::fs::write(input, vec![1u8; 4096]).unwrap();
+        std::fs::write(output, vec![1u8; 8192]).unwrap();
+
+        let mut cfg = Dds {
+            input: input.to_string(),
+            outputs: vec![output.to_string()],
+            backup: true,
+            ..Default::default()
+        };
+        let resize = |cfg: &Dds| {
+            create_image(cfg)?;
+            let o_file = open_output(output, true, false)?;
+            resize_image(cfg, o_file.as_file())
+        };
+
+        // a larger image is only shrunk when allowed to
+        let refused = resize(&cfg);
+        let untouched = std::fs::read(output).unwrap();
+        cfg.allow_truncate = true;
+        resize(&cfg).unwrap();
+        let shrunk = std::fs::read(output).unwrap();
+
+        std::fs::remove_file(output).unwrap();
+        resize(&cfg).unwrap();
+        let created = std::fs::read(output).unwrap();
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+
+        assert!(matches!(
+            refused,
+            Err(DdsError::SizeMismatch {
+                input: 4096,
+                output: 8192
+            })
+        ));
+        assert_eq!(untouched, vec![1u8; 8192]);
+        assert_eq!(shrunk, vec![1u8; 4096]);
+        assert_eq!(created, vec![0u8; 4096]);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use sha2::{Digest, Sha256};
+
+use indicatif::ProgressBar;
+
+use crate::{error::DdsError, flush::flush, notice, undo::UndoLog, Dds};
+
+/// How much of the input is scanned between checkpoints.
+pub const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// How much of the start of the input is hashed to identify it.
+const HEADER_HASH_SIZE: u64 = 1024 * 1024;
+
+const CHECKPOINT_HEADER: &str = "dds-checkpoint v1";
+
+/// Enough information about the input to tell if it has changed since a checkpoint was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputIdentity {
+    pub size: u64,
+    /// Modification time, in nanoseconds since the unix epoch
+    pub mtime: u128,
+    /// Hex encoded sha256 of the first `HEADER_HASH_SIZE` bytes of the input
+    pub header_hash: String,
+}
+
+impl InputIdentity {
+    pub fn of(path: &str) -> Result<InputIdentity, DdsError> {
+        let file = File::open(path).map_err(|e| DdsError::io("opening", path, None, e))?;
+        let metadata = file
+            .metadata()
+            .map_err(|e| DdsError::io("reading metadata of", path, None, e))?;
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file.take(HEADER_HASH_SIZE), &mut hasher)
+            .map_err(|e| DdsError::io("reading from", path, Some(0), e))?;
+
+        Ok(InputIdentity {
+            size: metadata.len(),
+            mtime,
+            header_hash: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// The last offset up to which the output is known to match the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub input: InputIdentity,
+    pub output: String,
+    pub offset: u64,
+}
+
+impl Checkpoint {
+    /// Where the checkpoint for this run lives unless told otherwise: next to the image, named
+    /// after both ends of the run so runs to other outputs never share one.
+    ///
+    /// The image is the input, or the output when backing up. Nothing is ever written next to a
+    /// device, a run between two devices keeps its checkpoint in the working directory instead.
+    pub fn path(cfg: &Dds) -> PathBuf {
+        if let Some(path) = &cfg.checkpoint {
+            return PathBuf::from(path);
+        }
+
+        let (image, other) = match cfg.backup {
+            true => (cfg.output(), cfg.input.as_str()),
+            false => (cfg.input.as_str(), cfg.output()),
+        };
+        let is_device = std::fs::metadata(image).is_ok_and(|metadata| !metadata.is_file());
+        let image = match is_device {
+            true => image.replace('/', "_"),
+            false => image.to_string(),
+        };
+        PathBuf::from(format!(
+            "{}.{}.dds-checkpoint",
+            image,
+            other.replace('/', "_")
+        ))
+    }
+
+    /// Load a checkpoint, returning `None` if there isn't one.
+    pub fn load(path: &Path) -> Result<Option<Checkpoint>, DdsError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(DdsError::io(
+                    "reading from",
+                    &path.display().to_string(),
+                    None,
+                    e,
+                ))
+            }
+        };
+
+        Checkpoint::parse(&contents)
+            .map(Some)
+            .ok_or_else(|| DdsError::Checkpoint {
+                path: path.display().to_string(),
+                reason: "the checkpoint is corrupt".to_string(),
+            })
+    }
+
+    fn parse(contents: &str) -> Option<Checkpoint> {
+        let mut lines = contents.lines();
+        if lines.next()? != CHECKPOINT_HEADER {
+            return None;
+        }
+
+        let (mut size, mut mtime, mut header_hash, mut output, mut offset) =
+            (None, None, None, None, None);
+        for line in lines {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "input_size" => size = Some(value.parse().ok()?),
+                "input_mtime" => mtime = Some(value.parse().ok()?),
+                "input_header_sha256" => header_hash = Some(value.to_string()),
+                "output" => output = Some(value.to_string()),
+                "offset" => offset = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+
+        Some(Checkpoint {
+            input: InputIdentity {
+                size: size?,
+                mtime: mtime?,
+                header_hash: header_hash?,
+            },
+            output: output?,
+            offset: offset?,
+        })
+    }
+
+    /// Write the checkpoint to disk, replacing any existing checkpoint atomically.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        // appended rather than swapping the extension, which could name someone else's file
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            write!(
+                file,
+                "{}\ninput_size={}\ninput_mtime={}\ninput_header_sha256={}\noutput={}\noffset={}\n",
+                CHECKPOINT_HEADER,
+                self.input.size,
+                self.input.mtime,
+                self.input.header_hash,
+                self.output,
+                self.offset
+            )?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+/// Work out where a run should start, which is the start of the file unless we are resuming.
+pub fn start_offset(cfg: &Dds, block_size: u64) -> Result<u64, DdsError> {
+    if !cfg.resume {
+        return Ok(0);
+    }
+
+    let path = Checkpoint::path(cfg);
+    let checkpoint = match Checkpoint::load(&path)? {
+        Some(checkpoint) => checkpoint,
+        None => {
+            notice!(
+                "No checkpoint found at {}, starting from the beginning",
+                path.display()
+            );
+            return Ok(0);
+        }
+    };
+
+    let mismatch = |reason: &str| DdsError::Checkpoint {
+        path: path.display().to_string(),
+        reason: reason.to_string(),
+    };
+    if checkpoint.output != cfg.output() {
+        return Err(mismatch("the checkpoint was taken for a different output"));
+    }
+    if checkpoint.input != InputIdentity::of(&cfg.input)? {
+        return Err(mismatch(
+            "the input has changed since the checkpoint was taken",
+        ));
+    }
+    if checkpoint.offset % block_size != 0 {
+        return Err(mismatch("the checkpoint is not aligned to the block size"));
+    }
+
+    notice!("Resuming from offset {}", checkpoint.offset);
+    Ok(checkpoint.offset)
+}
+
+/// Periodically records how far through the input a run has committed.
+pub struct Checkpointer {
+    path: PathBuf,
+    checkpoint: Checkpoint,
+    last_saved: u64,
+    /// With --sync-every, how much may be written before the output is synced
+    sync_every: Option<u64>,
+    /// How much has been written since the output was last synced
+    unsynced: u64,
+    /// Whether a checkpoint has failed to save, which is only worth saying once
+    failed: bool,
+    /// The undo log, synced before the output so every write that reaches the device can be undone
+    undo_log: Option<(String, File)>,
+}
+
+impl Checkpointer {
+    pub fn new(cfg: &Dds, start: u64) -> Result<Checkpointer, DdsError> {
+        Ok(Checkpointer {
+            path: Checkpoint::path(cfg),
+            checkpoint: Checkpoint {
+                input: InputIdentity::of(&cfg.input)?,
+                output: cfg.output().to_string(),
+                offset: start,
+            },
+            last_saved: start,
+            sync_every: cfg.sync_every.map(|bytes| bytes as u64),
+            unsynced: 0,
+            failed: false,
+            undo_log: None,
+        })
+    }
+
+    /// Sync `undo_log` every time the output is synced, before the output.
+    pub fn sync_undo_log(&mut self, undo_log: &UndoLog) -> Result<(), DdsError> {
+        self.undo_log = Some((undo_log.path().to_string(), undo_log.try_clone_file()?));
+        Ok(())
+    }
+
+    /// Count `written` bytes just written to the output, and save a checkpoint at `offset` if
+    /// enough of the input has been committed since the last one, or enough written for
+    /// --sync-every.
+    pub fn update(&mut self, o_file: &File, written: usize, offset: u64) {
+        self.unsynced += written as u64;
+        let sync_due = self.sync_every.is_some_and(|every| self.unsynced >= every);
+        if sync_due || offset.saturating_sub(self.last_saved) >= CHECKPOINT_INTERVAL {
+            self.save(o_file, offset);
+        }
+    }
+
+    /// Record that everything before `offset` has been written, once the output has been synced.
+    ///
+    /// Failing to save a checkpoint only loses the ability to resume, so it's reported, once, rather
+    /// than stopping the run.
+    pub fn save(&mut self, o_file: &File, offset: u64) {
+        self.checkpoint.offset = offset;
+        self.last_saved = offset;
+        self.unsynced = 0;
+
+        let result = self
+            .undo_log
+            .as_ref()
+            .map_or(Ok(()), |(_, undo_log)| undo_log.sync_data())
+            .and_then(|_| o_file.sync_data())
+            .and_then(|_| self.checkpoint.save(&self.path));
+        match result {
+            Err(e) if !self.failed => {
+                notice!(
+                    "Unable to save checkpoint to {}, this run can't be resumed: {}",
+                    self.path.display(),
+                    e
+                );
+                self.failed = true;
+            }
+            _ => (),
+        }
+    }
+
+    /// Wait for everything written to the output, and the undo log recording it, to reach the disk.
+    pub fn flush(&self, o_file: &File, output: &str, pb: ProgressBar) -> Result<(), DdsError> {
+        if let Some((path, undo_log)) = &self.undo_log {
+            undo_log
+                .sync_data()
+                .map_err(|e| DdsError::io("syncing", path, None, e))?;
+        }
+        flush(o_file, output, pb)
+    }
+
+    /// The run completed, so there is nothing left to resume.
+    pub fn finish(self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Unable to remove checkpoint {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{start_offset, Checkpoint, Checkpointer, InputIdentity};
+    use crate::{error::DdsError, Dds};
+
+    fn identity() -> InputIdentity {
+        InputIdentity {
+            size: 1024,
+            mtime: 1_666_000_000_123_456_789,
+            header_hash: "abcd".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path = Path::new("test_checkpoint_round_trip.dds-checkpoint");
+        let checkpoint = Checkpoint {
+            input: identity(),
+            output: "/dev/sda".to_string(),
+            offset: 5120,
+        };
+
+        checkpoint.save(path).unwrap();
+        let loaded = Checkpoint::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded, Some(checkpoint));
+    }
+
+    #[test]
+    fn test_checkpoint_save_leaves_other_files_alone() {
+        let path = Path::new("test_checkpoint_save_leaves_other_files_alone.img.dds-checkpoint");
+        let neighbour = "test_checkpoint_save_leaves_other_files_alone.img.tmp";
+        std::fs::write(neighbour, b"not ours").unwrap();
+
+        let checkpoint = Checkpoint {
+            input: identity(),
+            output: "/dev/sda".to_string(),
+            offset: 0,
+        };
+        checkpoint.save(path).unwrap();
+        let neighbour_after = std::fs::read(neighbour).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(neighbour).unwrap();
+
+        assert_eq!(neighbour_after, b"not ours");
+    }
+
+    #[test]
+    fn test_default_path() {
+        let path = |input: &str, output: &str, backup: bool| {
+            let cfg = Dds {
+                input: input.to_string(),
+                outputs: vec![output.to_string()],
+                backup,
+                ..Default::default()
+            };
+            Checkpoint::path(&cfg)
+        };
+
+        // runs restoring one image to several cards keep their own checkpoints
+        assert_eq!(
+            path("sda.img", "/dev/sdb", false),
+            Path::new("sda.img._dev_sdb.dds-checkpoint")
+        );
+        assert_ne!(
+            path("sda.img", "/dev/sdb", false),
+            path("sda.img", "/dev/sdc", false)
+        );
+        // a backup keeps it next to the image, not the device
+        assert_eq!(
+            path("/dev/sdb", "sda.img", true),
+            Path::new("sda.img._dev_sdb.dds-checkpoint")
+        );
+        // and a run between devices keeps it in the working directory
+        assert_eq!(
+            path("/dev/null", "/dev/sdb", false),
+            Path::new("_dev_null._dev_sdb.dds-checkpoint")
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_missing_is_none() {
+        let path = Path::new("test_checkpoint_missing.dds-checkpoint");
+        assert_eq!(Checkpoint::load(path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_checkpoint_corrupt() {
+        assert_eq!(Checkpoint::parse("dds-checkpoint v1\noffset=12\n"), None);
+        assert_eq!(Checkpoint::parse("something else\n"), None);
+        assert_eq!(
+            Checkpoint::parse("dds-checkpoint v1\noffset=twelve\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_start_offset_rejects_changed_input() {
+        let input = "test_start_offset_rejects_changed_input.bin";
+        std::fs::write(input, vec![1u8; 4096]).unwrap();
+
+        let cfg = Dds {
+            input: input.to_string(),
+            outputs: vec!["output.bin".to_string()],
+            resume: true,
+            ..Default::default()
+        };
+
+        // a checkpoint for the current input is accepted
+        let file = std::fs::File::open(input).unwrap();
+        let mut checkpointer = Checkpointer::new(&cfg, 0).unwrap();
+        checkpointer.save(&file, 2048);
+        assert_eq!(start_offset(&cfg, 512).unwrap(), 2048);
+
+        // but not once the input has been modified
+        std::fs::write(input, vec![2u8; 4096]).unwrap();
+        let err = start_offset(&cfg, 512).unwrap_err();
+
+        checkpointer.finish();
+        std::fs::remove_file(input).unwrap();
+
+        assert!(matches!(err, DdsError::Checkpoint { .. }));
+    }
+
+    #[test]
+    fn test_sync_every_saves_checkpoints() {
+        let input = "test_sync_every_saves_checkpoints.bin";
+        std::fs::write(input, vec![1u8; 4096]).unwrap();
+
+        let cfg = Dds {
+            input: input.to_string(),
+            outputs: vec!["output.bin".to_string()],
+            resume: true,
+            sync_every: Some(4096),
+            ..Default::default()
+        };
+        let file = std::fs::File::open(input).unwrap();
+        let mut checkpointer = Checkpointer::new(&cfg, 0).unwrap();
+
+        // nothing is saved until enough has been written
+        checkpointer.update(&file, 1024, 1024);
+        let before = Checkpoint::load(&Checkpoint::path(&cfg)).unwrap();
+        checkpointer.update(&file, 3072, 2048);
+        let after = Checkpoint::load(&Checkpoint::path(&cfg)).unwrap();
+
+        checkpointer.finish();
+        std::fs::remove_file(input).unwrap();
+
+        assert_eq!(before, None);
+        assert_eq!(after.map(|checkpoint| checkpoint.offset), Some(2048));
+    }
+}
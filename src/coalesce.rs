@@ -0,0 +1,140 @@
+use std::ops::Range;
+
+/// How nearby dirty chunks are merged into fewer, larger writes that line up with the erase blocks
+/// of flash media.
+///
+/// Rewriting a few clean bytes between two dirty chunks costs far less than making the FTL
+/// perform two separate read-modify-write cycles on the same erase block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coalesce {
+    /// The size of an erase block on the output, no write will cross an erase block boundary
+    pub erase_block: usize,
+    /// Dirty chunks separated by at most this many clean bytes are written together
+    pub max_gap: usize,
+}
+
+impl Coalesce {
+    /// Merge the dirty `ranges` of a buffer of length `limit`.
+    ///
+    /// The buffer must start on an erase block boundary, and the ranges must be sorted and not
+    /// overlap.
+    pub fn merge(&self, ranges: &[Range<usize>], limit: usize) -> Vec<Range<usize>> {
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        let mut window_merged = 0;
+        let mut window = usize::MAX;
+
+        for range in ranges {
+            let range_window = range.start / self.erase_block;
+
+            if range_window != window {
+                self.extend_to_window(&mut merged[window_merged..], window, limit);
+                window = range_window;
+                window_merged = merged.len();
+                merged.push(range.clone());
+                continue;
+            }
+
+            // only merge with ranges in the same erase block
+            let last = merged.last_mut().expect("window has at least one range");
+            if range.start - last.end <= self.max_gap && range.end <= self.window_end(window, limit)
+            {
+                last.end = range.end;
+            } else {
+                merged.push(range.clone());
+            }
+        }
+        self.extend_to_window(&mut merged[window_merged..], window, limit);
+
+        merged
+    }
+
+    fn window_end(&self, window: usize, limit: usize) -> usize {
+        ((window + 1) * self.erase_block).min(limit)
+    }
+
+    /// Grow the first and last ranges of an erase block out to its edges, if they are close.
+    fn extend_to_window(&self, ranges: &mut [Range<usize>], window: usize, limit: usize) {
+        if ranges.is_empty() {
+            return;
+        }
+
+        let window_start = window * self.erase_block;
+        let window_end = self.window_end(window, limit);
+
+        let first = ranges.first_mut().unwrap();
+        if first.start - window_start <= self.max_gap {
+            first.start = window_start;
+        }
+        let last = ranges.last_mut().unwrap();
+        if window_end.saturating_sub(last.end) <= self.max_gap {
+            last.end = last.end.max(window_end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coalesce;
+
+    #[test]
+    fn test_merge_small_gaps() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 512,
+        };
+        let merged = coalesce.merge(&[1024..1536, 2048..2560], 8192);
+        assert_eq!(merged, vec![1024..2560]);
+    }
+
+    #[test]
+    fn test_merge_keeps_large_gaps() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 256,
+        };
+        let merged = coalesce.merge(&[1024..1536, 3072..3584], 8192);
+        assert_eq!(merged, vec![1024..1536, 3072..3584]);
+    }
+
+    #[test]
+    fn test_merge_extends_to_erase_block() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 512,
+        };
+        let merged = coalesce.merge(&[512..1024, 2048..4096 - 512], 8192);
+        assert_eq!(merged, vec![0..1024, 2048..4096]);
+
+        let merged = coalesce.merge(&[512..1024, 1024..1536, 1536..2048, 2048..3584], 8192);
+        assert_eq!(merged, vec![0..4096]);
+    }
+
+    #[test]
+    fn test_merge_never_crosses_erase_blocks() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 4096,
+        };
+        let merged = coalesce.merge(&[3584..4096, 4096..4608], 8192);
+        assert_eq!(merged, vec![0..4096, 4096..8192]);
+    }
+
+    #[test]
+    fn test_merge_respects_limit() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 1024,
+        };
+        let merged = coalesce.merge(&[4096..4608, 4608..4096 + 1000], 4096 + 1000);
+        assert_eq!(merged, vec![4096..4096 + 1000]);
+    }
+
+    #[test]
+    fn test_merge_nothing() {
+        let coalesce = Coalesce {
+            erase_block: 4096,
+            max_gap: 1024,
+        };
+        assert!(coalesce.merge(&[], 8192).is_empty());
+    }
+}
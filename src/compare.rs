@@ -0,0 +1,224 @@
+use std::ops::Range;
+
+/// Every `chunk_size` chunk of the first `limit` bytes where `input` and `output` differ.
+///
+/// Each chunk is compared once, a SIMD lane at a time where the CPU has them, and only until its
+/// first difference, so a block is never compared as a whole and then again chunk by chunk.
+pub fn dirty_chunks(
+    input: &[u8],
+    output: &[u8],
+    limit: usize,
+    chunk_size: usize,
+) -> Vec<Range<usize>> {
+    let (input, output) = (&input[..limit], &output[..limit]);
+
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU was just checked to support AVX2
+        return unsafe { avx2_dirty_chunks(input, output, chunk_size) };
+    }
+    scan(input, output, chunk_size)
+}
+
+/// Whether two slices of the same length hold the same bytes.
+pub fn equal(a: &[u8], b: &[u8]) -> bool {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx2") {
+        // SAFETY: the CPU was just checked to support AVX2
+        return unsafe { avx2_equal(a, b) };
+    }
+    words_equal(a, b)
+}
+
+/// How many bytes are compared, in 32 byte AVX2 lanes, between each check for a difference.
+#[cfg(target_arch = "x86_64")]
+const AVX2_STRIDE: usize = 32 * 16;
+
+/// `scan`, comparing a 32 byte AVX2 lane at a time.
+///
+/// When chunks are a whole number of strides, the block is scanned in one go, jumping to the
+/// next chunk as soon as one is found to differ.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_dirty_chunks(input: &[u8], output: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    if !chunk_size.is_multiple_of(AVX2_STRIDE) {
+        let mut dirty = Vec::new();
+        for (i, (a, b)) in input
+            .chunks(chunk_size)
+            .zip(output.chunks(chunk_size))
+            .enumerate()
+        {
+            if !avx2_equal(a, b) {
+                let start = i * chunk_size;
+                dirty.push(start..start + a.len());
+            }
+        }
+        return dirty;
+    }
+
+    let len = input.len();
+    let split = len - len % AVX2_STRIDE;
+    let mut dirty = Vec::new();
+    let mut offset = 0;
+    while offset < split {
+        if avx2_stride_equal(input, output, offset) {
+            offset += AVX2_STRIDE;
+            continue;
+        }
+        let start = offset - offset % chunk_size;
+        offset = (start + chunk_size).min(len);
+        dirty.push(start..offset);
+    }
+
+    // what's left is the end of the last chunk, unless it's already been found to differ
+    if offset < len && !words_equal(&input[offset..], &output[offset..]) {
+        dirty.push(offset - offset % chunk_size..len);
+    }
+    dirty
+}
+
+/// `words_equal`, comparing a 32 byte AVX2 lane at a time.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_equal(a: &[u8], b: &[u8]) -> bool {
+    let split = a.len() - a.len() % AVX2_STRIDE;
+    (0..split)
+        .step_by(AVX2_STRIDE)
+        .all(|offset| avx2_stride_equal(a, b, offset))
+        && words_equal(&a[split..], &b[split..])
+}
+
+/// Whether `AVX2_STRIDE` bytes at `offset` are the same in both slices.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn avx2_stride_equal(a: &[u8], b: &[u8], offset: usize) -> bool {
+    use std::arch::x86_64::{
+        __m256i, _mm256_loadu_si256, _mm256_or_si256, _mm256_setzero_si256, _mm256_testz_si256,
+        _mm256_xor_si256,
+    };
+
+    let (a, b) = (
+        &a[offset..offset + AVX2_STRIDE],
+        &b[offset..offset + AVX2_STRIDE],
+    );
+    let mut diff = _mm256_setzero_si256();
+    for lane in (0..AVX2_STRIDE).step_by(32) {
+        // SAFETY: both slices hold a whole stride, and unaligned loads have no alignment
+        // requirement
+        let a = _mm256_loadu_si256(a.as_ptr().add(lane) as *const __m256i);
+        let b = _mm256_loadu_si256(b.as_ptr().add(lane) as *const __m256i);
+        diff = _mm256_or_si256(diff, _mm256_xor_si256(a, b));
+    }
+    _mm256_testz_si256(diff, diff) == 1
+}
+
+/// The portable `dirty_chunks`, for CPUs without AVX2.
+#[inline(always)]
+fn scan(input: &[u8], output: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    let mut dirty = Vec::new();
+    for (i, (a, b)) in input
+        .chunks(chunk_size)
+        .zip(output.chunks(chunk_size))
+        .enumerate()
+    {
+        if !words_equal(a, b) {
+            let start = i * chunk_size;
+            dirty.push(start..start + a.len());
+        }
+    }
+    dirty
+}
+
+/// The portable comparison, eight bytes at a time.
+///
+/// Differences are gathered into an accumulator rather than branched on, which lets the compiler
+/// turn the loop into SIMD for whatever the target supports, checking it every `STRIDE` bytes.
+#[inline(always)]
+fn words_equal(a: &[u8], b: &[u8]) -> bool {
+    const STRIDE: usize = 256;
+    let word = |bytes: &[u8]| u64::from_ne_bytes(bytes.try_into().expect("chunks of eight"));
+
+    let (a_strides, b_strides) = (a.chunks_exact(STRIDE), b.chunks_exact(STRIDE));
+    let (a_rest, b_rest) = (a_strides.remainder(), b_strides.remainder());
+    for (a, b) in a_strides.zip(b_strides) {
+        let diff = a
+            .chunks_exact(8)
+            .zip(b.chunks_exact(8))
+            .fold(0, |diff, (a, b)| diff | (word(a) ^ word(b)));
+        if diff != 0 {
+            return false;
+        }
+    }
+    a_rest == b_rest
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{dirty_chunks, equal, scan, words_equal};
+
+    #[test]
+    fn test_equal_finds_every_difference() {
+        // lengths around the stride and word size, with a difference at every position
+        for len in [0, 1, 7, 8, 255, 256, 257, 512, 1000] {
+            let a: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert!(equal(&a, &a.clone()));
+            assert!(words_equal(&a, &a.clone()));
+
+            for i in 0..len {
+                let mut b = a.clone();
+                b[i] ^= 0x80;
+                assert!(!equal(&a, &b), "len {} differing at {}", len, i);
+                assert!(!words_equal(&a, &b), "len {} differing at {}", len, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dirty_chunks() {
+        let input = vec![0u8; 4096];
+        let mut output = input.clone();
+        output[0] = 1;
+        output[2048 + 511] = 1;
+        output[4000] = 1;
+
+        assert_eq!(
+            dirty_chunks(&input, &output, 4096, 512),
+            vec![0..512, 2048..2560, 3584..4096]
+        );
+        // a partial last chunk stops at the limit
+        assert_eq!(
+            dirty_chunks(&input, &output, 4001, 512),
+            vec![0..512, 2048..2560, 3584..4001]
+        );
+        assert!(dirty_chunks(&input, &input, 4096, 512).is_empty());
+    }
+
+    #[test]
+    fn test_dirty_chunks_matches_a_naive_compare() {
+        let mut rng = rand::thread_rng();
+        // chunks that are, and aren't, a whole number of SIMD strides, and blocks that end partway
+        // through a chunk
+        for chunk_size in [256, 512, 1024, 1536, 4096] {
+            for limit in [chunk_size * 8, chunk_size * 8 - 100] {
+                let input = vec![0u8; chunk_size * 8];
+                let mut output = input.clone();
+                for _ in 0..6 {
+                    output[rng.gen_range(0..limit)] = 1;
+                }
+
+                let naive: Vec<_> = (0..limit)
+                    .step_by(chunk_size)
+                    .map(|start| start..(start + chunk_size).min(limit))
+                    .filter(|chunk| input[chunk.clone()] != output[chunk.clone()])
+                    .collect();
+                assert_eq!(dirty_chunks(&input, &output, limit, chunk_size), naive);
+                assert_eq!(scan(&input[..limit], &output[..limit], chunk_size), naive);
+            }
+        }
+    }
+}
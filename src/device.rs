@@ -0,0 +1,292 @@
+use std::{
+    fmt::Display,
+    fs::{File, Metadata},
+    io::{Seek, SeekFrom},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use indicatif::HumanBytes;
+
+/// `_IOR(0x12, 114, size_t)`, the ioctl that returns the size of a block device in bytes.
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+)))]
+const BLKGETSIZE64: libc::Ioctl = 0x8008_1272u32 as libc::Ioctl;
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+))]
+const BLKGETSIZE64: libc::Ioctl = 0x4008_1272u32 as libc::Ioctl;
+
+/// The direction bits of an `_IO` ioctl, which aren't zero on every architecture.
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+)))]
+const IOC_NONE: u32 = 0;
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+))]
+const IOC_NONE: u32 = 0x2000_0000;
+
+/// `_IO(0x12, nr)`, one of the block device ioctls.
+const fn blk_io(nr: u32) -> libc::Ioctl {
+    (IOC_NONE | (0x12 << 8) | nr) as libc::Ioctl
+}
+
+/// Returns the logical sector size of a block device.
+pub const BLKSSZGET: libc::Ioctl = blk_io(104);
+/// Discards a `[offset, length]` range of a block device.
+pub const BLKDISCARD: libc::Ioctl = blk_io(119);
+/// Zeroes a `[offset, length]` range of a block device, unmapping it where the device can.
+pub const BLKZEROOUT: libc::Ioctl = blk_io(127);
+
+/// Whether the file is a block device, rather than a regular file.
+pub fn is_block_device(file: &File) -> std::io::Result<bool> {
+    Ok(file.metadata()?.file_type().is_block_device())
+}
+
+/// The size of a file or block device in bytes.
+///
+/// Block devices report a length of 0 in their metadata, so their size is asked of the kernel.
+pub fn size(file: &File) -> std::io::Result<u64> {
+    if !is_block_device(file)? {
+        return Ok(file.metadata()?.len());
+    }
+
+    let mut size: u64 = 0;
+    // SAFETY: BLKGETSIZE64 writes a single u64 into `size`, and the descriptor is valid.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size as *mut u64) };
+    if res == 0 {
+        return Ok(size);
+    }
+
+    // fall back to finding the end of the device, leaving the cursor where it was
+    let mut file = file;
+    let position = file.stream_position()?;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(position))?;
+    Ok(size)
+}
+
+/// The smallest unit a block device can be written in, which discards must be aligned to.
+pub fn sector_size(file: &File) -> std::io::Result<u64> {
+    let mut size: libc::c_int = 0;
+    // SAFETY: BLKSSZGET writes a single int into `size`, and the descriptor is valid.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut size as *mut libc::c_int) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(size as u64)
+}
+
+/// Where the kernel describes a block device in sysfs, e.g. `/sys/dev/block/8:0`, or `None` for
+/// a regular file.
+pub fn sysfs_dir(metadata: &Metadata) -> Option<PathBuf> {
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+    let rdev = metadata.rdev();
+    Some(PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(rdev),
+        libc::minor(rdev)
+    )))
+}
+
+/// A partition of a block device, as the kernel currently sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    /// Where the partition starts on the device, in bytes
+    pub start: u64,
+    pub size: u64,
+}
+
+/// What is about to be overwritten, shown before asking the user to confirm.
+#[derive(Debug)]
+pub struct Description {
+    pub path: String,
+    pub model: Option<String>,
+    pub size: u64,
+    /// `None` for a regular file
+    pub partitions: Option<Vec<Partition>>,
+}
+
+/// sysfs always counts in 512 byte sectors, whatever the device's sector size.
+const SYSFS_SECTOR_SIZE: u64 = 512;
+
+fn read_sysfs(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// The partitions of the block device described by `dir`, sorted by where they start.
+pub fn partitions_in(dir: &Path) -> std::io::Result<Vec<Partition>> {
+    let sectors = |path: PathBuf| -> Option<u64> { read_sysfs(&path)?.parse().ok() };
+
+    let mut partitions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.join("partition").exists() {
+            continue;
+        }
+        if let (Some(start), Some(size)) = (sectors(path.join("start")), sectors(path.join("size")))
+        {
+            partitions.push(Partition {
+                name: path.file_name().unwrap().to_string_lossy().to_string(),
+                start: start * SYSFS_SECTOR_SIZE,
+                size: size * SYSFS_SECTOR_SIZE,
+            });
+        }
+    }
+    partitions.sort_by_key(|partition| partition.start);
+    Ok(partitions)
+}
+
+/// Describe the file or device at `path`, so the user knows what they are about to overwrite.
+pub fn describe(path: &str) -> std::io::Result<Description> {
+    let file = File::open(path)?;
+    let size = size(&file)?;
+
+    let (model, partitions) = match sysfs_dir(&file.metadata()?) {
+        Some(dir) => {
+            // a partition's model belongs to the disk it is on
+            let model = read_sysfs(&dir.join("device/model"))
+                .or_else(|| read_sysfs(&dir.join("../device/model")));
+            (model, Some(partitions_in(&dir)?))
+        }
+        None => (None, None),
+    };
+
+    Ok(Description {
+        path: path.to_string(),
+        model,
+        size,
+        partitions,
+    })
+}
+
+impl Display for Description {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match (&self.partitions, &self.model) {
+            (None, _) => "regular file",
+            (Some(_), Some(model)) => model,
+            (Some(_), None) => "unknown model",
+        };
+        writeln!(f, "{}: {}, {}", self.path, kind, HumanBytes(self.size))?;
+
+        match &self.partitions {
+            Some(partitions) if partitions.is_empty() => writeln!(f, "  no partitions"),
+            Some(partitions) => {
+                for partition in partitions {
+                    writeln!(
+                        f,
+                        "  {} at {}, {}",
+                        partition.name,
+                        HumanBytes(partition.start),
+                        HumanBytes(partition.size)
+                    )?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{
+        describe, is_block_device, partitions_in, size, Partition, BLKDISCARD, BLKSSZGET,
+        BLKZEROOUT,
+    };
+
+    #[test]
+    fn test_size_of_regular_file() {
+        let path = "test_size_of_regular_file.bin";
+        std::fs::write(path, vec![0u8; 12345]).unwrap();
+
+        let file = std::fs::File::open(path).unwrap();
+        let result = (is_block_device(&file).unwrap(), size(&file).unwrap());
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(result, (false, 12345));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_block_ioctl_numbers() {
+        assert_eq!(BLKSSZGET, 0x1268);
+        assert_eq!(BLKDISCARD, 0x1277);
+        assert_eq!(BLKZEROOUT, 0x127f);
+    }
+
+    #[test]
+    fn test_partitions_in_sysfs() {
+        let dir = Path::new("test_partitions_in_sysfs");
+        for (name, start, size) in [("sdz2", "526336", "1000"), ("sdz1", "2048", "524288")] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join("partition"), "1\n").unwrap();
+            std::fs::write(dir.join(name).join("start"), start).unwrap();
+            std::fs::write(dir.join(name).join("size"), size).unwrap();
+        }
+        // attributes of the disk itself aren't partitions
+        std::fs::create_dir_all(dir.join("queue")).unwrap();
+
+        let partitions = partitions_in(dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(
+            partitions,
+            vec![
+                Partition {
+                    name: "sdz1".to_string(),
+                    start: 1024 * 1024,
+                    size: 256 * 1024 * 1024,
+                },
+                Partition {
+                    name: "sdz2".to_string(),
+                    start: 526336 * 512,
+                    size: 1000 * 512,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_describe_regular_file() {
+        let path = "test_describe_regular_file.bin";
+        std::fs::write(path, vec![0u8; 2048]).unwrap();
+
+        let description = describe(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            description.to_string(),
+            "test_describe_regular_file.bin: regular file, 2.00 KiB\n"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+};
+
+use crate::{device, notice};
+
+/// The alignment of every buffer handed to `O_DIRECT` reads and writes, which satisfies the
+/// logical block size of almost every device.
+pub const DIRECT_ALIGNMENT: usize = 4096;
+
+/// The largest write copied into an aligned buffer at once.
+const BOUNCE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A zeroed heap buffer whose start is aligned for `O_DIRECT`.
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// SAFETY: the buffer is uniquely owned, just like a `Vec<u8>`.
+unsafe impl Send for AlignedBuffer {}
+// SAFETY: a shared buffer can only be read, just like a `&[u8]`.
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> AlignedBuffer {
+        let layout =
+            Layout::from_size_align(len.max(1), DIRECT_ALIGNMENT).expect("buffer size overflows");
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` is valid for `layout.size()` initialised bytes for the buffer's lifetime.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and `&mut self` guarantees the access is unique.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout` and is only freed here.
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// A file that reads and writes with `O_DIRECT` where it can, bypassing the page cache.
+///
+/// `O_DIRECT` needs the buffer, offset and length of every transfer to be aligned, so anything
+/// that isn't, or a filesystem that doesn't support it at all such as tmpfs, goes through a
+/// second, buffered descriptor instead.
+pub struct DirectFile {
+    file: File,
+    direct: Option<File>,
+    /// The alignment `O_DIRECT` transfers need on this file
+    alignment: u64,
+    position: u64,
+    bounce: Option<AlignedBuffer>,
+}
+
+impl DirectFile {
+    /// Open the file, and if `write` is set and it is a block device, claim it with `O_EXCL`.
+    pub fn open(path: &str, write: bool, direct: bool) -> std::io::Result<DirectFile> {
+        let exclusive = write
+            && std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .custom_flags(if exclusive { libc::O_EXCL } else { 0 })
+            .open(path)?;
+
+        let direct = match direct {
+            // only one descriptor can claim a block device, so this one doesn't
+            true => match OpenOptions::new()
+                .read(true)
+                .write(write)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)
+            {
+                Ok(direct) => Some(direct),
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    notice!(
+                        "{} doesn't support direct I/O, using the page cache instead",
+                        path
+                    );
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            false => None,
+        };
+
+        let alignment = match device::is_block_device(&file)? {
+            true => device::sector_size(&file)?,
+            false => file.metadata()?.blksize(),
+        };
+
+        Ok(DirectFile {
+            file,
+            direct,
+            alignment,
+            position: 0,
+            bounce: None,
+        })
+    }
+
+    /// Another handle to the same open file, sharing its locks, with its own position.
+    pub fn try_clone(&self) -> std::io::Result<DirectFile> {
+        Ok(DirectFile {
+            file: self.file.try_clone()?,
+            direct: self.direct.as_ref().map(File::try_clone).transpose()?,
+            alignment: self.alignment,
+            position: self.position,
+            bounce: None,
+        })
+    }
+
+    /// The buffered descriptor, for anything other than reading and writing.
+    pub fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    fn is_aligned(&self, ptr: *const u8, len: usize, offset: u64) -> bool {
+        self.alignment <= DIRECT_ALIGNMENT as u64
+            && (ptr as usize).is_multiple_of(DIRECT_ALIGNMENT)
+            && (len as u64).is_multiple_of(self.alignment)
+            && offset.is_multiple_of(self.alignment)
+    }
+
+    /// Stop using `O_DIRECT` if the kernel turns a transfer down, rather than failing the run.
+    fn fall_back(&mut self, e: &std::io::Error) -> bool {
+        if e.raw_os_error() != Some(libc::EINVAL) {
+            return false;
+        }
+        notice!(
+            "Direct I/O was refused ({}), using the page cache instead",
+            e
+        );
+        self.direct = None;
+        true
+    }
+
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if self.is_aligned(buf.as_ptr(), buf.len(), offset) {
+            if let Some(direct) = &self.direct {
+                match direct.read_at(buf, offset) {
+                    Err(e) if self.fall_back(&e) => {}
+                    result => return result,
+                }
+            }
+        }
+        self.file.read_at(buf, offset)
+    }
+
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        let len = buf.len().min(BOUNCE_BUFFER_SIZE);
+        if self.direct.is_some() && self.is_aligned(std::ptr::null(), len, offset) {
+            let bounce = self
+                .bounce
+                .get_or_insert_with(|| AlignedBuffer::new(BOUNCE_BUFFER_SIZE));
+            bounce[..len].copy_from_slice(&buf[..len]);
+
+            if let Some(direct) = &self.direct {
+                match direct.write_at(&bounce[..len], offset) {
+                    Err(e) if self.fall_back(&e) => {}
+                    result => return result,
+                }
+            }
+        }
+        self.file.write_at(buf, offset)
+    }
+}
+
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.write_at(buf, self.position)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(_) | SeekFrom::Current(_) => {
+                self.file.seek(SeekFrom::Start(self.position))?;
+                self.file.seek(pos)?
+            }
+        };
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{AlignedBuffer, DirectFile, DIRECT_ALIGNMENT};
+
+    #[test]
+    fn test_aligned_buffer() {
+        let buffer = AlignedBuffer::new(5120);
+        assert_eq!(buffer.len(), 5120);
+        assert_eq!(buffer.as_ptr() as usize % DIRECT_ALIGNMENT, 0);
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_direct_file_round_trip() {
+        let path = "test_direct_file_round_trip.bin";
+        std::fs::write(path, vec![1u8; 64 * 1024]).unwrap();
+        // works whether or not the filesystem supports O_DIRECT
+        let mut file = DirectFile::open(path, true, true).unwrap();
+
+        // aligned and unaligned writes both land
+        file.seek(SeekFrom::Start(8192)).unwrap();
+        file.write_all(&[2u8; 8192]).unwrap();
+        file.seek(SeekFrom::Start(100)).unwrap();
+        file.write_all(&[3u8; 10]).unwrap();
+
+        let mut buffer = AlignedBuffer::new(64 * 1024);
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buffer).unwrap(), 64 * 1024);
+        std::fs::remove_file(path).unwrap();
+
+        let mut expected = vec![1u8; 64 * 1024];
+        expected[8192..16384].fill(2);
+        expected[100..110].fill(3);
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+}
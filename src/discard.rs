@@ -0,0 +1,219 @@
+use std::{
+    fs::File,
+    os::unix::{fs::FileExt, io::AsRawFd},
+};
+
+use crate::{
+    compare::equal,
+    device::{self, BLKDISCARD, BLKZEROOUT},
+    notice,
+};
+
+/// How much of a discarded range is read back at once to check it now reads as zeros.
+const CHECK_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Zeros to compare blocks against, a chunk at a time.
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/// How zeroed regions are cleared from the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// `BLKDISCARD`, checking the device reads the range back as zeros afterwards
+    Discard,
+    /// `BLKZEROOUT`, for devices that can't discard or don't return zeros for discarded ranges
+    ZeroOut,
+    /// `fallocate(FALLOC_FL_PUNCH_HOLE)` on a regular file
+    PunchHole,
+    /// The output can't do any of these, so zeros are written as normal
+    Unsupported,
+}
+
+/// Clears zeroed regions of the output without writing the zeros, letting the FTL of a flash
+/// device reclaim them.
+#[derive(Debug)]
+pub struct Discarder {
+    method: Method,
+    /// Ranges must start and end on a multiple of this
+    alignment: u64,
+    /// The total number of bytes cleared rather than written
+    pub discarded: u64,
+}
+
+pub(crate) fn is_zero(data: &[u8]) -> bool {
+    data.chunks(ZEROS.len())
+        .all(|chunk| equal(chunk, &ZEROS[..chunk.len()]))
+}
+
+impl Discarder {
+    pub fn new(file: &File) -> std::io::Result<Discarder> {
+        let (method, alignment) = match device::is_block_device(file)? {
+            true => (Method::Discard, device::sector_size(file)?),
+            false => (Method::PunchHole, 1),
+        };
+        Ok(Discarder {
+            method,
+            alignment,
+            discarded: 0,
+        })
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Tell the user how much was discarded, once the run is over.
+    pub fn report(&self) {
+        if self.discarded > 0 {
+            notice!(
+                "Discarded {} bytes of zeros instead of writing them",
+                self.discarded
+            );
+        } else if self.method == Method::Unsupported {
+            notice!("The output doesn't support discarding, zeros were written instead");
+        }
+    }
+
+    /// Try to clear `data`, which is due to be written at `offset`, returning `false` if it has
+    /// to be written as normal.
+    pub fn discard(&mut self, file: &File, offset: u64, data: &[u8]) -> std::io::Result<bool> {
+        let len = data.len() as u64;
+        if len == 0
+            || !offset.is_multiple_of(self.alignment)
+            || !len.is_multiple_of(self.alignment)
+            || !is_zero(data)
+        {
+            return Ok(false);
+        }
+
+        loop {
+            let result = match self.method {
+                Method::Discard => blk_range(file, BLKDISCARD, offset, len)
+                    .and_then(|_| reads_as_zero(file, offset, len)),
+                Method::ZeroOut => blk_range(file, BLKZEROOUT, offset, len).map(|_| true),
+                Method::PunchHole => punch_hole(file, offset, len).map(|_| true),
+                Method::Unsupported => return Ok(false),
+            };
+
+            match result {
+                Ok(true) => {
+                    self.discarded += len;
+                    return Ok(true);
+                }
+                // the device discarded the range but doesn't promise zeros, so zero it out instead
+                Ok(false) => self.method = Method::ZeroOut,
+                Err(e) if is_unsupported(&e) => {
+                    self.method = match self.method {
+                        Method::Discard => Method::ZeroOut,
+                        _ => Method::Unsupported,
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn is_unsupported(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL)
+    )
+}
+
+fn blk_range(file: &File, request: libc::Ioctl, offset: u64, len: u64) -> std::io::Result<()> {
+    let range: [u64; 2] = [offset, len];
+    // SAFETY: the request takes a pointer to a `[start, length]` pair of u64s, which outlives the
+    // call, and the file descriptor is valid.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), request, range.as_ptr()) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read a range back to check it is all zeros.
+fn reads_as_zero(file: &File, offset: u64, len: u64) -> std::io::Result<bool> {
+    let mut buf = vec![0u8; CHECK_BUFFER_SIZE.min(len as usize)];
+    let mut checked = 0;
+    while checked < len {
+        let chunk = &mut buf[..(len - checked).min(CHECK_BUFFER_SIZE as u64) as usize];
+        file.read_exact_at(chunk, offset + checked)?;
+        if !is_zero(chunk) {
+            return Ok(false);
+        }
+        checked += chunk.len() as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    use super::{is_zero, Discarder, Method};
+
+    #[test]
+    fn test_is_zero() {
+        for len in [0, 1, 4095, 4096, 4097, 10000] {
+            let mut data = vec![0u8; len];
+            assert!(is_zero(&data));
+            if len > 0 {
+                data[len - 1] = 1;
+                assert!(!is_zero(&data), "len {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_punch_hole_in_regular_file() {
+        let path = "test_punch_hole_in_regular_file.bin";
+        std::fs::write(path, vec![1u8; 1024 * 1024]).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let blocks_before = file.metadata().unwrap().blocks();
+
+        let mut discarder = Discarder::new(&file).unwrap();
+        assert_eq!(discarder.method(), Method::PunchHole);
+
+        // only zeros are ever discarded
+        assert!(!discarder.discard(&file, 0, &[1u8; 4096]).unwrap());
+        let discarded = discarder
+            .discard(&file, 4096, &vec![0u8; 512 * 1024])
+            .unwrap();
+
+        let mut data = vec![1u8; 1024 * 1024];
+        file.read_exact_at(&mut data, 0).unwrap();
+        let blocks_after = file.metadata().unwrap().blocks();
+        std::fs::remove_file(path).unwrap();
+
+        if discarded {
+            assert_eq!(discarder.discarded, 512 * 1024);
+            assert!(blocks_after < blocks_before);
+            assert!(data[4096..4096 + 512 * 1024].iter().all(|&b| b == 0));
+        } else {
+            // the filesystem can't punch holes, so the zeros have to be written instead
+            assert_eq!(discarder.method(), Method::Unsupported);
+        }
+        assert!(data[..4096].iter().all(|&b| b == 1));
+        assert!(data[4096 + 512 * 1024..].iter().all(|&b| b == 1));
+    }
+}
@@ -1,2 +1,185 @@
+use std::{fmt::Display, io};
+
+use crate::report::DiffReport;
+
 #[derive(Debug)]
-pub enum DdsError {}
+pub enum DdsError {
+    /// Opening, reading, seeking or syncing one of the files failed
+    Io {
+        /// What we were trying to do, e.g. "reading from"
+        action: &'static str,
+        path: String,
+        /// Where in the file the operation was taking place, if known
+        offset: Option<u64>,
+        source: io::Error,
+    },
+    /// Writing a block to the output failed
+    Write {
+        offset: u64,
+        len: usize,
+        source: io::Error,
+    },
+    /// The options given don't make sense together
+    InvalidConfig(String),
+    /// The input or output path does not exist
+    MissingPath { name: &'static str, path: String },
+    /// The input and output are not the same size
+    SizeMismatch { input: u64, output: u64 },
+    /// A worker thread could not be started, or panicked
+    Thread { name: String, reason: String },
+    /// A checkpoint could not be resumed from
+    Checkpoint { path: String, reason: String },
+    /// A patch could not be read, or applying it did not recreate its input
+    Patch { path: String, reason: String },
+    /// An undo log could not be read, or undoing it did not restore the output
+    Undo { path: String, reason: String },
+    /// The output was read back and did not match the input
+    VerifyFailed(DiffReport),
+    /// The output is claimed by another program
+    Locked { path: String, reason: String },
+    /// The user declined to overwrite the output
+    Aborted,
+    /// The output holds a mounted filesystem, which writing to would corrupt
+    Mounted { output: String, reason: String },
+    /// There was no terminal to ask the user before overwriting the output
+    Unconfirmed(String),
+    /// Some of the outputs of a run given several could not be written
+    OutputsFailed { failed: usize, total: usize },
+    /// An output of a run given several made no progress for too long, and was left behind
+    Stalled { output: String, secs: u64 },
+}
+
+impl DdsError {
+    pub fn io(action: &'static str, path: &str, offset: Option<u64>, source: io::Error) -> Self {
+        DdsError::Io {
+            action,
+            path: path.to_string(),
+            offset,
+            source,
+        }
+    }
+
+    pub fn thread(name: &str, reason: impl Display) -> Self {
+        DdsError::Thread {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Display for DdsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DdsError::Io {
+                action,
+                path,
+                offset: Some(offset),
+                source,
+            } => write!(
+                f,
+                "Error {} {} at offset {}: {}",
+                action, path, offset, source
+            ),
+            DdsError::Io {
+                action,
+                path,
+                offset: None,
+                source,
+            } => write!(f, "Error {} {}: {}", action, path, source),
+            DdsError::Write {
+                offset,
+                len,
+                source,
+            } => write!(
+                f,
+                "Error writing {} bytes to the output at offset {}: {}",
+                len, offset, source
+            ),
+            DdsError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            DdsError::MissingPath { name, path } => {
+                write!(f, "{} file {} does not exist", name, path)
+            }
+            DdsError::SizeMismatch { input, output } => write!(
+                f,
+                "Input is {} bytes but the output is {} bytes",
+                input, output
+            ),
+            DdsError::Thread { name, reason } => write!(f, "{} thread failed: {}", name, reason),
+            DdsError::Checkpoint { path, reason } => {
+                write!(f, "Unable to resume from checkpoint {}: {}", path, reason)
+            }
+            DdsError::Patch { path, reason } => {
+                write!(f, "Unable to apply patch {}: {}", path, reason)
+            }
+            DdsError::Undo { path, reason } => {
+                write!(f, "Unable to undo from {}: {}", path, reason)
+            }
+            DdsError::VerifyFailed(report) => write!(
+                f,
+                "Verification failed, the output does not match the input\n{}",
+                report
+            ),
+            DdsError::Mounted { output, reason } => write!(
+                f,
+                "Refusing to write to {}, {}. Pass --allow-mounted to write to it anyway",
+                output, reason
+            ),
+            DdsError::Locked { path, reason } => {
+                write!(f, "Unable to lock {}: {}", path, reason)
+            }
+            DdsError::Aborted => write!(f, "Aborting"),
+            DdsError::Unconfirmed(output) => write!(
+                f,
+                "Not overwriting {} without confirmation, stdin isn't a terminal so pass --yes to go ahead",
+                output
+            ),
+            DdsError::OutputsFailed { failed, total } => {
+                write!(f, "{} of {} outputs failed", failed, total)
+            }
+            DdsError::Stalled { output, secs } => write!(
+                f,
+                "{} made no progress for {}s, left it behind so the run could finish",
+                output, secs
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DdsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DdsError::Io { source, .. } | DdsError::Write { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DdsError;
+
+    #[test]
+    fn test_io_error_display_includes_offset() {
+        let err = DdsError::io(
+            "reading from",
+            "/dev/sda",
+            Some(5120),
+            std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+        );
+        assert_eq!(
+            err.to_string(),
+            "Error reading from /dev/sda at offset 5120: unexpected end of file"
+        );
+    }
+
+    #[test]
+    fn test_io_error_source() {
+        let err = DdsError::Write {
+            offset: 0,
+            len: 512,
+            source: std::io::Error::from(std::io::ErrorKind::WriteZero),
+        };
+        assert!(std::error::Error::source(&err).is_some());
+        assert!(std::error::Error::source(&DdsError::Aborted).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use indicatif::ProgressDrawTarget;
+use serde::Serialize;
+
+use crate::stats::Summary;
+
+/// How often progress events are emitted at most.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The output the events from this thread are about, in a run given several
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// How a run reports what it is doing.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Progress bars and messages for a person at a terminal
+    #[default]
+    Human,
+    /// A JSON event per line on stdout, with messages moved to stderr
+    Json,
+}
+
+pub fn set_format(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Tag every event emitted from this thread with `output`, so the events of a run given several
+/// outputs can be told apart.
+pub fn tag_output(output: &str) {
+    OUTPUT.with(|tag| *tag.borrow_mut() = Some(output.to_string()));
+}
+
+/// Print a message for the user, which goes to stderr when stdout is reserved for JSON events.
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        if $crate::events::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Where progress bars are drawn, nowhere when emitting JSON.
+pub fn draw_target() -> ProgressDrawTarget {
+    match is_json() {
+        true => ProgressDrawTarget::hidden(),
+        false => ProgressDrawTarget::stderr(),
+    }
+}
+
+/// Something that happened during a run, emitted as a line of JSON with `--output-format json`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Start {
+        input: &'a str,
+        output: &'a str,
+        /// How many bytes will be compared
+        size: u64,
+        threaded: bool,
+        dry_run: bool,
+    },
+    Progress {
+        bytes_compared: u64,
+        size: u64,
+    },
+    Write {
+        offset: u64,
+        len: usize,
+        blocks: usize,
+    },
+    Verify {
+        passed: bool,
+        mismatched_bytes: u64,
+    },
+    Finish {
+        summary: Summary,
+    },
+    /// How one output of a run given several went, emitted for each once they have all finished
+    Output {
+        output: &'a str,
+        error: Option<String>,
+        summary: Option<Summary>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event<'_> {
+    pub fn emit(&self) {
+        if is_json() {
+            println!("{}", self.to_json());
+        }
+    }
+
+    /// The event as a line of JSON, with the output this thread is tagged with if it has one.
+    fn to_json(&self) -> String {
+        // an event is only made of strings and numbers, so it always serialises
+        match OUTPUT.with(|tag| tag.borrow().clone()) {
+            Some(output) => {
+                let mut event = serde_json::to_value(self).unwrap();
+                if let Some(fields) = event.as_object_mut() {
+                    fields.entry("output").or_insert(output.into());
+                }
+                event.to_string()
+            }
+            None => serde_json::to_string(self).unwrap(),
+        }
+    }
+}
+
+/// Emits progress events as the compare moves along, without flooding the output.
+#[derive(Debug)]
+pub struct ProgressEvents {
+    size: u64,
+    last: Instant,
+}
+
+impl ProgressEvents {
+    pub fn new(size: u64) -> ProgressEvents {
+        ProgressEvents {
+            size,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, bytes_compared: u64) {
+        if self.last.elapsed() >= PROGRESS_INTERVAL {
+            self.last = Instant::now();
+            Event::Progress {
+                bytes_compared,
+                size: self.size,
+            }
+            .emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tag_output, Event};
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::Write {
+            offset: 5120,
+            len: 1024,
+            blocks: 2,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"write","offset":5120,"len":1024,"blocks":2}"#
+        );
+    }
+
+    #[test]
+    fn test_tag_output() {
+        let write = Event::Write {
+            offset: 0,
+            len: 512,
+            blocks: 1,
+        };
+        let start = Event::Start {
+            input: "a.img",
+            output: "/dev/sdc",
+            size: 1024,
+            threaded: false,
+            dry_run: false,
+        };
+
+        // only events from the thread working on the output are tagged
+        let (write_json, start_json) = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tag_output("/dev/sdb");
+                    (write.to_json(), start.to_json())
+                })
+                .join()
+                .unwrap()
+        });
+        let write_json: serde_json::Value = serde_json::from_str(&write_json).unwrap();
+        let start_json: serde_json::Value = serde_json::from_str(&start_json).unwrap();
+
+        assert_eq!(write_json["output"], "/dev/sdb");
+        assert_eq!(write_json["len"], 512);
+        // an event that names its output already keeps it
+        assert_eq!(start_json["output"], "/dev/sdc");
+        assert!(!write.to_json().contains("output"));
+    }
+}
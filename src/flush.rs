@@ -0,0 +1,26 @@
+use std::{fs::File, time::Duration};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::error::DdsError;
+
+/// Wait for everything written to the output to reach the device, showing a spinner meanwhile.
+///
+/// Writes sit in the page cache long after the compare finishes, so a run isn't done, and the
+/// device isn't safe to unplug, until this returns.
+pub fn flush(o_file: &File, output: &str, pb: ProgressBar) -> Result<(), DdsError> {
+    pb.set_style(
+        ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {msg}").unwrap(),
+    );
+    pb.set_message(format!("Flushing writes to {}", output));
+    pb.enable_steady_tick(Duration::from_millis(100));
+
+    let result = o_file
+        .sync_all()
+        .map_err(|e| DdsError::io("syncing", output, None, e));
+    match &result {
+        Ok(()) => pb.finish_with_message(format!("Synced {}, it is safe to remove", output)),
+        Err(_) => pb.abandon_with_message(format!("Unable to sync {}", output)),
+    }
+    result
+}
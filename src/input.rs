@@ -0,0 +1,382 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    os::unix::io::AsRawFd,
+};
+
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::{device, direct::DirectFile, error::DdsError, reader::read_full};
+
+/// How much of a compressed input is read from disk at once.
+const COMPRESSED_BUFFER_SIZE: usize = 1024 * 1024;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How an input image is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Work out the compression of a file from its first few bytes.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+enum Source {
+    Plain(DirectFile),
+    Sparse(SparseFile),
+    Compressed(Box<dyn Read + Send>),
+}
+
+/// A regular file whose holes are read as zeros without touching the disk.
+struct SparseFile {
+    file: DirectFile,
+    size: u64,
+    position: u64,
+    /// The extent the position was last found to be in, which is data or a hole
+    extent: std::ops::Range<u64>,
+    in_hole: bool,
+    holes_skipped: u64,
+}
+
+impl SparseFile {
+    /// Use `SEEK_DATA` to find the holes in the file, handing the file back if the filesystem can't.
+    fn new(file: DirectFile, size: u64) -> Result<SparseFile, DirectFile> {
+        let mut sparse = SparseFile {
+            file,
+            size,
+            position: 0,
+            extent: 0..0,
+            in_hole: false,
+            holes_skipped: 0,
+        };
+        match sparse.find_extent() {
+            Ok(()) => Ok(sparse),
+            Err(_) => Err(sparse.file),
+        }
+    }
+
+    fn lseek(&self, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+        // SAFETY: the file descriptor is valid for the lifetime of `self.file`.
+        let res = unsafe {
+            libc::lseek(
+                self.file.as_file().as_raw_fd(),
+                self.position as libc::off_t,
+                whence,
+            )
+        };
+        if res >= 0 {
+            return Ok(Some(res as u64));
+        }
+        match std::io::Error::last_os_error() {
+            // there is no more data after the position, so the rest of the file is a hole
+            e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            e => Err(e),
+        }
+    }
+
+    /// Find the data or hole that the current position lies in.
+    fn find_extent(&mut self) -> std::io::Result<()> {
+        match self.lseek(libc::SEEK_DATA)? {
+            Some(data) if data == self.position => {
+                let hole = self.lseek(libc::SEEK_HOLE)?.unwrap_or(self.size);
+                self.extent = self.position..hole;
+                self.in_hole = false;
+            }
+            data => {
+                self.extent = self.position..data.unwrap_or(self.size);
+                self.in_hole = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        if !self.extent.contains(&self.position) {
+            self.find_extent()?;
+        }
+
+        let len = buf.len().min((self.extent.end - self.position) as usize);
+        let read = match self.in_hole {
+            true => {
+                buf[..len].fill(0);
+                self.holes_skipped += len as u64;
+                len
+            }
+            false => self.file.read_at(&mut buf[..len], self.position)?,
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// The input image, decompressing it on the fly if needed.
+///
+/// Holes in a sparse input are read as zeros, without reading the zeros from the disk.
+///
+/// A compressed input can only be read front to back, and its size isn't known until it has all
+/// been read.
+pub struct Input {
+    source: Source,
+    size: Option<u64>,
+    pub compression: Compression,
+    position: u64,
+    /// Once the input runs out, zeros are read up to this length
+    padded_to: Option<u64>,
+}
+
+impl Input {
+    /// Open the input, reading an uncompressed one with `O_DIRECT` if `direct` is set.
+    pub fn open(path: &str, direct: bool) -> Result<Input, DdsError> {
+        let mut file = File::open(path).map_err(|e| DdsError::io("opening", path, None, e))?;
+
+        let mut header = [0u8; 6];
+        let read = read_full(&mut file, &mut header)
+            .and_then(|read| file.seek(SeekFrom::Start(0)).map(|_| read))
+            .map_err(|e| DdsError::io("reading from", path, Some(0), e))?;
+
+        let compression = Compression::detect(&header[..read]);
+        let source: Box<dyn Read + Send> = match compression {
+            Compression::None => {
+                let file = DirectFile::open(path, false, direct)
+                    .map_err(|e| DdsError::io("opening", path, None, e))?;
+                let size = device::size(file.as_file())
+                    .map_err(|e| DdsError::io("finding the size of", path, None, e))?;
+                let is_block_device = device::is_block_device(file.as_file())
+                    .map_err(|e| DdsError::io("reading metadata of", path, None, e))?;
+
+                // only regular files can have holes
+                let source = match is_block_device {
+                    true => Source::Plain(file),
+                    false => SparseFile::new(file, size).map_or_else(Source::Plain, Source::Sparse),
+                };
+                return Ok(Input {
+                    source,
+                    size: Some(size),
+                    compression,
+                    position: 0,
+                    padded_to: None,
+                });
+            }
+            Compression::Gzip => Box::new(MultiGzDecoder::new(buffered(file))),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(buffered(file))),
+            Compression::Zstd => Box::new(
+                zstd::stream::read::Decoder::with_buffer(buffered(file))
+                    .map_err(|e| DdsError::io("decompressing", path, Some(0), e))?,
+            ),
+        };
+
+        Ok(Input {
+            source: Source::Compressed(source),
+            size: None,
+            compression,
+            position: 0,
+            padded_to: None,
+        })
+    }
+
+    /// The size of the image including any padding, or `None` if it is compressed.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+            .map(|size| size.max(self.padded_to.unwrap_or_default()))
+    }
+
+    /// How many bytes have been read from holes in the input, rather than from the disk.
+    #[cfg(test)]
+    pub fn holes_skipped(&self) -> u64 {
+        match &self.source {
+            Source::Sparse(sparse) => sparse.holes_skipped,
+            _ => 0,
+        }
+    }
+
+    /// Move to `offset` in the image, which for a compressed input means decompressing everything
+    /// before it.
+    pub fn skip_to(&mut self, offset: u64) -> std::io::Result<()> {
+        match &mut self.source {
+            Source::Plain(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+            }
+            Source::Sparse(sparse) => sparse.position = offset,
+            Source::Compressed(reader) => {
+                let skipped = std::io::copy(&mut reader.take(offset), &mut std::io::sink())?;
+                if skipped != offset && self.padded_to.is_none_or(|padded| padded < offset) {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        self.position = offset;
+        Ok(())
+    }
+
+    /// Read zeros once the input runs out, until it is `len` bytes long.
+    pub fn pad_to(&mut self, len: u64) {
+        self.padded_to = Some(len);
+    }
+}
+
+fn buffered(file: File) -> BufReader<File> {
+    BufReader::with_capacity(COMPRESSED_BUFFER_SIZE, file)
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = match &mut self.source {
+            Source::Plain(file) => file.read(buf)?,
+            Source::Sparse(sparse) => sparse.read(buf)?,
+            Source::Compressed(reader) => reader.read(buf)?,
+        };
+
+        if let Some(padded_to) = self.padded_to {
+            if read == 0 && self.position < padded_to {
+                read = buf.len().min((padded_to - self.position) as usize);
+                buf[..read].fill(0);
+            }
+        }
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// The length of the progress bar for a run, which follows the output when the input's size isn't
+/// known up front.
+pub fn progress_length(input: &Input, o_file: &File, output: &str) -> Result<u64, DdsError> {
+    match input.size() {
+        Some(size) => Ok(size),
+        None => {
+            device::size(o_file).map_err(|e| DdsError::io("finding the size of", output, None, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::fs::FileExt,
+    };
+
+    use super::{Compression, Input};
+
+    fn compressed(compression: Compression, data: &[u8]) -> Vec<u8> {
+        match compression {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_detect_compression() {
+        let data = b"not compressed at all";
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            assert_eq!(
+                Compression::detect(&compressed(compression, data)),
+                compression
+            );
+        }
+        assert_eq!(Compression::detect(&[]), Compression::None);
+    }
+
+    #[test]
+    fn test_read_compressed_inputs() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        for (compression, path) in [
+            (Compression::None, "test_read_compressed_inputs.img"),
+            (Compression::Gzip, "test_read_compressed_inputs.img.gz"),
+            (Compression::Xz, "test_read_compressed_inputs.img.xz"),
+            (Compression::Zstd, "test_read_compressed_inputs.img.zst"),
+        ] {
+            std::fs::write(path, compressed(compression, &data)).unwrap();
+
+            let mut input = Input::open(path, false).unwrap();
+            input.skip_to(1000).unwrap();
+            let mut read = Vec::new();
+            input.read_to_end(&mut read).unwrap();
+            std::fs::remove_file(path).unwrap();
+
+            assert_eq!(input.compression, compression);
+            assert_eq!(input.size().is_some(), compression == Compression::None);
+            assert_eq!(read, &data[1000..]);
+        }
+    }
+
+    #[test]
+    fn test_read_sparse_input() {
+        let path = "test_read_sparse_input.img";
+        let file = std::fs::File::create(path).unwrap();
+        file.set_len(16 * 1024 * 1024).unwrap();
+        file.write_all_at(&[1u8; 4096], 0).unwrap();
+        file.write_all_at(&[2u8; 4096], 8 * 1024 * 1024).unwrap();
+        drop(file);
+
+        let mut input = Input::open(path, false).unwrap();
+        input.skip_to(1024).unwrap();
+        let mut read = Vec::new();
+        input.read_to_end(&mut read).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut expected = vec![0u8; 16 * 1024 * 1024];
+        expected[..4096].fill(1);
+        expected[8 * 1024 * 1024..8 * 1024 * 1024 + 4096].fill(2);
+        assert_eq!(read, &expected[1024..]);
+
+        // most of the file is never read from the disk
+        assert!(input.holes_skipped() > 15 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_pad_input() {
+        let path = "test_pad_input.img.zst";
+        std::fs::write(path, compressed(Compression::Zstd, &[1u8; 1000])).unwrap();
+
+        let mut input = Input::open(path, false).unwrap();
+        input.pad_to(3000);
+        input.skip_to(500).unwrap();
+        let mut read = Vec::new();
+        input.read_to_end(&mut read).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut expected = vec![1u8; 500];
+        expected.resize(2500, 0);
+        assert_eq!(read, expected);
+    }
+}
@@ -1,36 +1,306 @@
 #[macro_use]
 extern crate static_assertions;
 
-use clap::{Command, Parser, ValueHint};
+use clap::{ArgAction, Args, Command, Parser, Subcommand, ValueHint};
 use clap_complete::{generate, Generator, Shell};
+use coalesce::Coalesce;
+use error::DdsError;
+use events::OutputFormat;
 use std::io::stdout;
+use utils::parse_size;
 
+pub mod backup;
+pub mod checkpoint;
+pub mod coalesce;
+pub mod compare;
+pub mod device;
+pub mod direct;
+pub mod discard;
 pub mod error;
+pub mod events;
+pub mod flush;
+pub mod input;
+pub mod lock;
+pub mod mounts;
+pub mod multi;
+pub mod patch;
+pub mod pool;
+pub mod reader;
+pub mod report;
 pub mod single;
+pub mod size;
+pub mod stats;
 pub mod threaded;
+pub mod undo;
 pub mod utils;
+pub mod verify;
 
-const BLOCK_SIZE: usize = 1024 * 5;
-const MIN_BLOCK_SIZE: usize = 512;
+/// The default amount read from the input and output at once
+pub const BLOCK_SIZE: usize = 1024 * 5;
+/// The default amount read at once with --direct, `BLOCK_SIZE` rounded up to what O_DIRECT needs
+pub const DIRECT_BLOCK_SIZE: usize = 1024 * 8;
+/// The default size of the smallest region that is compared and written
+pub const MIN_BLOCK_SIZE: usize = 512;
+/// The largest block that may be read at once
+const MAX_BLOCK_SIZE: usize = 1024 * 1024 * 1024;
 
 const_assert!(BLOCK_SIZE >= MIN_BLOCK_SIZE);
-const_assert!(BLOCK_SIZE % MIN_BLOCK_SIZE == 0);
-const_assert!(BLOCK_SIZE % 2 == 0);
-const_assert!(BLOCK_SIZE < 1024 * 1024 * 1024);
+const_assert!(BLOCK_SIZE.is_multiple_of(MIN_BLOCK_SIZE));
+const_assert!(BLOCK_SIZE.is_multiple_of(2));
+const_assert!(BLOCK_SIZE < MAX_BLOCK_SIZE);
+const_assert!(DIRECT_BLOCK_SIZE.is_multiple_of(direct::DIRECT_ALIGNMENT));
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct Dds {
-    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    // the empty defaults are only ever used by subcommands, which take their own paths
+    #[arg(short, long, value_hint = ValueHint::FilePath, required = true, default_value = "", hide_default_value = true)]
     pub input: String,
-    #[arg(short, long, value_hint = ValueHint::FilePath)]
-    pub output: String,
+    /// Where to write the input, give more than once to write it to several outputs at once
+    #[arg(short = 'o', long = "output", value_hint = ValueHint::FilePath, required = true, default_value = "", hide_default_value = true, action = ArgAction::Append)]
+    pub outputs: Vec<String>,
 
     #[arg(short, long)]
     pub threaded: bool,
 
+    /// Overwrite the output without asking first, which is required when stdin isn't a terminal
+    #[arg(short, long, visible_alias = "force", global = true)]
+    pub yes: bool,
+
+    /// Write to the output even if it, or a partition on it, is mounted or holds the root filesystem
+    #[arg(long, global = true)]
+    pub allow_mounted: bool,
+
+    /// Refresh the image given by --output from the device given by --input, creating it if needed
+    #[arg(long)]
+    pub backup: bool,
+
+    /// Compare the input and output and report the regions that differ, without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Discard regions that should be zeroed, with TRIM on block devices or by punching holes in
+    /// regular files, instead of writing the zeros
+    #[arg(long, conflicts_with_all = ["dry_run", "export_patch"])]
+    pub discard: bool,
+
+    /// Read and write with O_DIRECT, bypassing the page cache, where the filesystem supports it.
+    /// --read-block must be a multiple of 4K, and defaults to 8K
+    #[arg(long)]
+    pub direct: bool,
+
+    /// If the output is smaller than the input, write as much of the input as fits
+    #[arg(long)]
+    pub allow_truncate: bool,
+
+    /// If the output is larger than the input, zero the rest of the output
+    #[arg(long, conflicts_with = "ignore_tail")]
+    pub zero_tail: bool,
+
+    /// If the output is larger than the input, leave the rest of the output as it is
+    #[arg(long)]
+    pub ignore_tail: bool,
+
+    /// Save the regions that differ into a patch file for `dds apply`, instead of writing them
+    #[arg(long, value_hint = ValueHint::FilePath, conflicts_with_all = ["dry_run", "verify", "resume", "backup"])]
+    pub export_patch: Option<String>,
+
+    /// Save the original contents of everything that is overwritten, so `dds undo` can roll the
+    /// run back
+    #[arg(long, value_hint = ValueHint::FilePath, conflicts_with_all = ["dry_run", "export_patch", "resume"])]
+    pub undo_log: Option<String>,
+
+    /// Once finished, read the output back and check every byte against the input
+    #[arg(long, conflicts_with = "dry_run")]
+    pub verify: bool,
+
+    /// Sync the output and save a checkpoint every time this much has been written, e.g. 64M
+    #[arg(long, value_parser = parse_size, conflicts_with_all = ["dry_run", "export_patch"])]
+    pub sync_every: Option<usize>,
+
+    /// Continue an interrupted run from its last checkpoint
+    #[arg(long, conflicts_with = "dry_run")]
+    pub resume: bool,
+
+    /// Where to keep the checkpoint used by --resume, defaults to next to the image
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub checkpoint: Option<String>,
+
+    /// How much of the input and output to read at once, e.g. 5K or 1M
+    #[arg(long, default_value_t = BLOCK_SIZE, default_value_if("direct", "true", "8K"), value_parser = parse_size)]
+    pub read_block: usize,
+
+    /// The size of the smallest region that is compared and rewritten, e.g. 512 or 4K
+    #[arg(long, default_value_t = MIN_BLOCK_SIZE, value_parser = parse_size)]
+    pub write_granularity: usize,
+
+    /// Merge nearby writes into writes aligned to erase blocks of this size, e.g. 128K
+    #[arg(long, value_parser = parse_size)]
+    pub erase_block: Option<usize>,
+
+    /// With --erase-block, rewrite up to this many unchanged bytes to merge two writes
+    #[arg(long, default_value_t = 4096, value_parser = parse_size, requires = "erase_block")]
+    pub coalesce_gap: usize,
+
+    /// How to report progress, `json` emits an event per line on stdout for other programs
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human, global = true)]
+    pub output_format: OutputFormat,
+
     #[arg(long = "generate", hide = true)]
     pub generate: Option<Shell>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Write a patch saved with --export-patch onto the output
+    Apply(ApplyArgs),
+    /// Put back everything a run saved with --undo-log overwrote
+    Undo(UndoArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ApplyArgs {
+    /// The patch to apply
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub patch: String,
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub output: String,
+
+    /// Once finished, check the output matches the checksum the patch was saved with
+    #[arg(long)]
+    pub verify: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct UndoArgs {
+    /// The undo log saved by the run
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub log: String,
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub output: String,
+
+    /// Once finished, check the output matches how it was before the run
+    #[arg(long)]
+    pub verify: bool,
+}
+
+impl Default for Dds {
+    /// The configuration `dds` runs with when only the paths are given, leaving them empty.
+    fn default() -> Self {
+        Dds::parse_from(["dds", "--input", "", "--output", ""])
+    }
+}
+
+impl Dds {
+    /// Whether the run changes the output, rather than only reading it.
+    pub fn writes_output(&self) -> bool {
+        !self.dry_run && self.export_patch.is_none()
+    }
+
+    /// The output a controller writes to, the first of `outputs`.
+    pub fn output(&self) -> &str {
+        self.outputs.first().map_or("", String::as_str)
+    }
+
+    /// The configuration for one output of a run given several, which keeps its own checkpoint.
+    pub fn for_output(&self, output: &str) -> Dds {
+        Dds {
+            outputs: vec![output.to_string()],
+            ..self.clone()
+        }
+    }
+
+    /// How writes should be coalesced, if at all.
+    pub fn coalesce(&self) -> Option<Coalesce> {
+        self.erase_block.map(|erase_block| Coalesce {
+            erase_block,
+            max_gap: self.coalesce_gap,
+        })
+    }
+
+    /// Check the options given at runtime hold the same invariants the defaults are held to.
+    pub fn validate(&self) -> Result<(), DdsError> {
+        let invalid = |reason: String| Err(DdsError::InvalidConfig(reason));
+
+        if self.write_granularity == 0 {
+            return invalid("--write-granularity must be greater than 0".to_string());
+        }
+        if self.read_block < self.write_granularity {
+            return invalid(format!(
+                "--read-block ({}) must be at least --write-granularity ({})",
+                self.read_block, self.write_granularity
+            ));
+        }
+        if !self.read_block.is_multiple_of(self.write_granularity) {
+            return invalid(format!(
+                "--read-block ({}) must be a multiple of --write-granularity ({})",
+                self.read_block, self.write_granularity
+            ));
+        }
+        if !self.read_block.is_multiple_of(2) {
+            return invalid(format!(
+                "--read-block ({}) must be a multiple of 2",
+                self.read_block
+            ));
+        }
+        if self.direct && !self.read_block.is_multiple_of(direct::DIRECT_ALIGNMENT) {
+            return invalid(format!(
+                "--read-block ({}) must be a multiple of {} to use --direct",
+                self.read_block,
+                direct::DIRECT_ALIGNMENT
+            ));
+        }
+        if self.read_block >= MAX_BLOCK_SIZE {
+            return invalid(format!(
+                "--read-block ({}) must be smaller than {}",
+                self.read_block, MAX_BLOCK_SIZE
+            ));
+        }
+
+        if self.outputs.len() > 1 {
+            let unsupported = [
+                (self.backup, "--backup"),
+                (self.export_patch.is_some(), "--export-patch"),
+                (self.undo_log.is_some(), "--undo-log"),
+                (self.resume, "--resume"),
+                (self.checkpoint.is_some(), "--checkpoint"),
+                (self.threaded, "--threaded"),
+            ];
+            if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+                return invalid(format!("{} can only be used with a single --output", flag));
+            }
+            for (i, output) in self.outputs.iter().enumerate() {
+                if self.outputs[..i].contains(output) {
+                    return invalid(format!("--output {} is given more than once", output));
+                }
+            }
+        }
+
+        if self.sync_every == Some(0) {
+            return invalid("--sync-every must be greater than 0".to_string());
+        }
+
+        if let Some(erase_block) = self.erase_block {
+            if erase_block == 0 || !erase_block.is_multiple_of(self.write_granularity) {
+                return invalid(format!(
+                    "--erase-block ({}) must be a multiple of --write-granularity ({})",
+                    erase_block, self.write_granularity
+                ));
+            }
+            if !self.read_block.is_multiple_of(erase_block) {
+                return invalid(format!(
+                    "--read-block ({}) must be a multiple of --erase-block ({})",
+                    self.read_block, erase_block
+                ));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
@@ -39,11 +309,171 @@ pub fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
 
 #[cfg(test)]
 mod tests {
-    use crate::Dds;
-    use clap::CommandFactory;
+    use crate::{
+        checkpoint::Checkpoint, error::DdsError, Commands, Dds, BLOCK_SIZE, DIRECT_BLOCK_SIZE,
+        MIN_BLOCK_SIZE,
+    };
+    use clap::{CommandFactory, Parser};
 
     #[test]
     fn verify_cli() {
         Dds::command().debug_assert();
     }
+
+    #[test]
+    fn test_default_block_sizes() {
+        let cfg = Dds::default();
+        assert_eq!(cfg.read_block, BLOCK_SIZE);
+        assert_eq!(cfg.write_granularity, MIN_BLOCK_SIZE);
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_block_sizes_from_cli() {
+        let cfg = Dds::parse_from([
+            "dds",
+            "--input",
+            "a",
+            "--output",
+            "b",
+            "--read-block",
+            "128K",
+            "--write-granularity",
+            "4K",
+        ]);
+        assert_eq!(cfg.read_block, 128 * 1024);
+        assert_eq!(cfg.write_granularity, 4 * 1024);
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_apply_does_not_need_input() {
+        let cfg = Dds::parse_from(["dds", "apply", "--patch", "a.patch", "--output", "b"]);
+        assert!(matches!(cfg.command, Some(Commands::Apply(args)) if args.patch == "a.patch"));
+
+        assert!(Dds::try_parse_from(["dds", "--output", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_undo_log_needs_a_fresh_run() {
+        let cfg = Dds::parse_from(["dds", "undo", "--log", "a.ddsundo", "--output", "b", "-y"]);
+        assert!(matches!(cfg.command, Some(Commands::Undo(args)) if args.log == "a.ddsundo"));
+        assert!(cfg.yes);
+
+        // resuming would start a new log, losing what the interrupted run overwrote
+        let with = |flag: &str| {
+            Dds::try_parse_from(["dds", "-i", "a", "-o", "b", "--undo-log", "c", flag]).is_ok()
+        };
+        assert!(!with("--resume"));
+        assert!(!with("--dry-run"));
+        assert!(with("--threaded"));
+    }
+
+    #[test]
+    fn test_several_outputs() {
+        let cfg = Dds::parse_from(["dds", "-i", "a", "-o", "b", "--output", "/dev/sdc"]);
+        assert_eq!(cfg.outputs, vec!["b", "/dev/sdc"]);
+        assert!(cfg.validate().is_ok());
+
+        // every output keeps its own checkpoint
+        let sdc = cfg.for_output("/dev/sdc");
+        assert_eq!(sdc.output(), "/dev/sdc");
+        assert_eq!(
+            Checkpoint::path(&sdc),
+            std::path::Path::new("a._dev_sdc.dds-checkpoint")
+        );
+        assert_ne!(
+            Checkpoint::path(&cfg.for_output("b")),
+            Checkpoint::path(&sdc)
+        );
+
+        let invalid = |args: &[&str]| {
+            let cfg = Dds::parse_from([&["dds", "-i", "a", "-o", "b", "-o", "c"], args].concat());
+            matches!(cfg.validate(), Err(DdsError::InvalidConfig(_)))
+        };
+        assert!(invalid(&["--resume"]));
+        assert!(invalid(&["--undo-log", "d"]));
+        assert!(invalid(&["--threaded"]));
+        assert!(invalid(&["-o", "b"]));
+        assert!(!invalid(&["--verify", "--discard"]));
+    }
+
+    #[test]
+    fn test_yes_applies_to_apply() {
+        let cfg = Dds::parse_from(["dds", "apply", "-p", "a.patch", "-o", "b", "--force"]);
+        assert!(cfg.yes);
+        assert!(Dds::parse_from(["dds", "-i", "a", "-o", "b", "-y"]).yes);
+        assert!(!Dds::default().yes);
+    }
+
+    #[test]
+    fn test_invalid_block_sizes() {
+        let invalid = |read_block, write_granularity| {
+            let cfg = Dds {
+                read_block,
+                write_granularity,
+                ..Default::default()
+            };
+            matches!(cfg.validate(), Err(DdsError::InvalidConfig(_)))
+        };
+
+        assert!(invalid(4096, 0));
+        assert!(invalid(512, 4096));
+        assert!(invalid(5000, 512));
+        assert!(invalid(1023, 1023));
+        assert!(invalid(1024 * 1024 * 1024, 512));
+        assert!(!invalid(4096, 4096));
+    }
+
+    #[test]
+    fn test_direct_needs_aligned_read_block() {
+        let direct = |read_block| Dds {
+            read_block,
+            direct: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            direct(BLOCK_SIZE).validate(),
+            Err(DdsError::InvalidConfig(_))
+        ));
+        assert!(direct(128 * 1024).validate().is_ok());
+
+        // only a block size that was asked for is turned down, the default is rounded up
+        let cfg = Dds::parse_from(["dds", "-i", "a", "-o", "b", "--direct"]);
+        assert_eq!(cfg.read_block, DIRECT_BLOCK_SIZE);
+        assert!(cfg.validate().is_ok());
+        let cfg = Dds::parse_from([
+            "dds",
+            "-i",
+            "a",
+            "-o",
+            "b",
+            "--direct",
+            "--read-block",
+            "5K",
+        ]);
+        assert!(matches!(cfg.validate(), Err(DdsError::InvalidConfig(_))));
+        assert_eq!(
+            Dds::parse_from(["dds", "-i", "a", "-o", "b"]).read_block,
+            BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn test_invalid_erase_block() {
+        let invalid = |read_block, erase_block| {
+            let cfg = Dds {
+                read_block,
+                erase_block: Some(erase_block),
+                ..Default::default()
+            };
+            matches!(cfg.validate(), Err(DdsError::InvalidConfig(_)))
+        };
+
+        assert!(invalid(128 * 1024, 0));
+        assert!(invalid(128 * 1024, 1000));
+        assert!(invalid(BLOCK_SIZE, 4096));
+        assert!(!invalid(128 * 1024, 4096));
+    }
 }
@@ -0,0 +1,68 @@
+use std::{fs::File, os::unix::io::AsRawFd};
+
+use crate::{direct::DirectFile, error::DdsError};
+
+/// Open the output for a run. When it is to be written, it is claimed for the rest of the run:
+/// a block device is opened with `O_EXCL`, so it can't be mounted or claimed by anyone else, and
+/// any output is `flock`ed, which other `dds` runs and udev respect.
+///
+/// The lock lasts until the file and every clone of it is closed.
+pub fn open_output(path: &str, write: bool, direct: bool) -> Result<DirectFile, DdsError> {
+    let file = DirectFile::open(path, write, direct).map_err(|e| match e.raw_os_error() {
+        Some(libc::EBUSY) if write => DdsError::Locked {
+            path: path.to_string(),
+            reason: "it is mounted or in use by another program".to_string(),
+        },
+        _ => DdsError::io("opening", path, None, e),
+    })?;
+
+    if write {
+        lock(file.as_file(), path)?;
+    }
+    Ok(file)
+}
+
+fn lock(file: &File, path: &str) -> Result<(), DdsError> {
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if res == 0 {
+        return Ok(());
+    }
+    match std::io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Err(DdsError::Locked {
+            path: path.to_string(),
+            reason: "another dds run, or another program, has it locked".to_string(),
+        }),
+        e => Err(DdsError::io("locking", path, None, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open_output;
+    use crate::error::DdsError;
+
+    #[test]
+    fn test_output_is_locked_while_open() {
+        let path = "test_output_is_locked_while_open.bin";
+        std::fs::write(path, vec![0u8; 4096]).unwrap();
+
+        let first = open_output(path, true, false).unwrap();
+        let clone = first.try_clone().unwrap();
+        let second = open_output(path, true, false);
+        // reading doesn't need the lock
+        let reader = open_output(path, false, false);
+
+        // the lock is held until every clone is closed
+        drop(first);
+        let while_cloned = open_output(path, true, false);
+        drop(clone);
+        let after = open_output(path, true, false);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(second, Err(DdsError::Locked { .. })));
+        assert!(reader.is_ok());
+        assert!(matches!(while_cloned, Err(DdsError::Locked { .. })));
+        assert!(after.is_ok());
+    }
+}
@@ -1,13 +1,83 @@
-use std::process::exit;
+use std::{io::IsTerminal, process::exit};
 
 use clap::{CommandFactory, Parser};
-use dds::{print_completions, single, threaded, Dds};
+use dds::{
+    device,
+    error::DdsError,
+    events::{self, Event},
+    mounts, multi, notice, patch, print_completions, single, threaded, undo, Commands, Dds,
+};
 use human_panic::setup_panic;
 
+fn confirm_overwrite(outputs: &[String], yes: bool) -> Result<(), DdsError> {
+    if yes {
+        return Ok(());
+    }
+    let names = outputs.join(", ");
+    // nobody is there to answer, so don't take whatever is piped in as a yes
+    if !std::io::stdin().is_terminal() {
+        return Err(DdsError::Unconfirmed(names));
+    }
+
+    // a missing output is reported once the run starts
+    for output in outputs {
+        if let Ok(description) = device::describe(output) {
+            notice!("{}", description.to_string().trim_end());
+        }
+    }
+    notice!("Are you sure you want to overwrite {}? (y/n)", names);
+    let mut input = String::new();
+    std::io::stdin()
+        .read_line(&mut input)
+        .map_err(|e| DdsError::io("reading from", "stdin", None, e))?;
+    if input.trim() != "y" {
+        return Err(DdsError::Aborted);
+    }
+    Ok(())
+}
+
+fn run(opt: Dds) -> Result<(), DdsError> {
+    if let Some(command) = &opt.command {
+        let output = match command {
+            Commands::Apply(args) => &args.output,
+            Commands::Undo(args) => &args.output,
+        };
+        if !opt.allow_mounted {
+            mounts::check_not_mounted(output)?;
+        }
+        confirm_overwrite(std::slice::from_ref(output), opt.yes)?;
+        return match command {
+            Commands::Apply(args) => patch::apply(args),
+            Commands::Undo(args) => undo::undo(args),
+        };
+    }
+
+    if opt.writes_output() {
+        // the controllers check again, this is so nobody is asked about a disk that can't be used
+        if !opt.allow_mounted {
+            opt.outputs
+                .iter()
+                .try_for_each(|output| mounts::check_not_mounted(output))?;
+        }
+        confirm_overwrite(&opt.outputs, opt.yes)?;
+    }
+
+    if opt.outputs.len() > 1 {
+        return multi::controller(opt);
+    }
+
+    if opt.threaded {
+        threaded::controller(opt)
+    } else {
+        single::controller(opt)
+    }
+}
+
 fn main() {
     setup_panic!();
 
     let opt = Dds::parse();
+    events::set_format(opt.output_format);
 
     if let Some(shell) = opt.generate {
         let mut cmd = Dds::command();
@@ -15,17 +85,12 @@ fn main() {
         exit(0);
     }
 
-    println!("Are you sure you want to overwrite {}? (y/n)", &opt.output);
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input).unwrap();
-    if input.trim() != "y" {
-        eprintln!("Aborting");
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        Event::Error {
+            message: e.to_string(),
+        }
+        .emit();
         exit(1);
     }
-
-    if opt.threaded {
-        threaded::controller(opt).unwrap();
-    } else {
-        single::controller(opt).unwrap();
-    }
 }
@@ -0,0 +1,233 @@
+use std::{
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+};
+
+use crate::{device, error::DdsError};
+
+/// A device number, as `(major, minor)`.
+pub type DeviceId = (u32, u32);
+
+/// A mounted filesystem, from a line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub device: DeviceId,
+    pub mount_point: String,
+}
+
+/// Undo the octal escapes mountinfo uses for spaces and other awkward characters in paths.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_device_id(value: &str) -> Option<DeviceId> {
+    let (major, minor) = value.trim().split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn device_id_of(path: &Path) -> Option<DeviceId> {
+    let metadata = std::fs::metadata(path).ok()?;
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+    Some((libc::major(metadata.rdev()), libc::minor(metadata.rdev())))
+}
+
+/// Parse `/proc/self/mountinfo`, skipping any line that doesn't make sense.
+pub fn parse_mountinfo(contents: &str) -> Vec<Mount> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let mut device = parse_device_id(fields.get(2)?)?;
+            let mount_point = unescape(fields.get(4)?);
+
+            // filesystems like btrfs report an anonymous device number, but name the real one
+            let separator = fields.iter().position(|&field| field == "-")?;
+            if let Some(source) = fields.get(separator + 2) {
+                if source.starts_with("/dev/") {
+                    device = device_id_of(Path::new(&unescape(source))).unwrap_or(device);
+                }
+            }
+
+            Some(Mount {
+                device,
+                mount_point,
+            })
+        })
+        .collect()
+}
+
+/// The block devices that share storage with the device described by `dir` in sysfs: the device
+/// itself, its partitions, and anything built on top of them such as LVM or dm-crypt volumes.
+pub fn related_devices(dir: &Path) -> Vec<(String, DeviceId)> {
+    let mut related = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let id = std::fs::read_to_string(dir.join("dev"))
+            .ok()
+            .and_then(|dev| parse_device_id(&dev));
+        let name = std::fs::canonicalize(&dir)
+            .unwrap_or_else(|_| dir.clone())
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match id {
+            Some(id) if !related.iter().any(|(_, seen)| *seen == id) => related.push((name, id)),
+            _ => continue,
+        }
+
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                if entry.path().join("partition").exists() {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        if let Ok(holders) = std::fs::read_dir(dir.join("holders")) {
+            pending.extend(holders.flatten().map(|holder| holder.path()));
+        }
+    }
+    related
+}
+
+/// Why writing over the `related` devices would clobber a mounted filesystem, if it would.
+pub fn find_conflict(related: &[(String, DeviceId)], mounts: &[Mount]) -> Option<String> {
+    let mounted = |mount: &Mount| {
+        related
+            .iter()
+            .find(|(_, id)| *id == mount.device)
+            .map(|(name, _)| name)
+    };
+
+    if let Some(root) = mounts.iter().find(|mount| mount.mount_point == "/") {
+        if let Some(name) = mounted(root) {
+            return Some(format!("{} holds the running root filesystem", name));
+        }
+    }
+    mounts.iter().find_map(|mount| {
+        mounted(mount).map(|name| format!("{} is mounted at {}", name, mount.mount_point))
+    })
+}
+
+/// Refuse to write to a block device that is mounted, holds the root filesystem, or has mounted
+/// partitions or volumes on it.
+pub fn check_not_mounted(output: &str) -> Result<(), DdsError> {
+    // a missing output is reported elsewhere
+    let dir = match std::fs::metadata(output).map(|metadata| device::sysfs_dir(&metadata)) {
+        Ok(Some(dir)) => dir,
+        _ => return Ok(()),
+    };
+
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| DdsError::io("reading from", "/proc/self/mountinfo", None, e))?;
+    match find_conflict(&related_devices(&dir), &parse_mountinfo(&mountinfo)) {
+        Some(reason) => Err(DdsError::Mounted {
+            output: output.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{find_conflict, parse_mountinfo, related_devices, Mount};
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /nonexistent/sda2 rw
+23 22 0:22 / /proc rw,relatime shared:5 - proc proc rw
+35 22 8:17 / /media/my\\040card rw,relatime shared:30 - vfat /nonexistent/sdb1 rw
+not a mount at all
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(
+            mounts,
+            vec![
+                Mount {
+                    device: (8, 2),
+                    mount_point: "/".to_string()
+                },
+                Mount {
+                    device: (0, 22),
+                    mount_point: "/proc".to_string()
+                },
+                Mount {
+                    device: (8, 17),
+                    mount_point: "/media/my card".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_conflict() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+
+        let sda = [("sda".to_string(), (8, 0)), ("sda2".to_string(), (8, 2))];
+        assert_eq!(
+            find_conflict(&sda, &mounts).unwrap(),
+            "sda2 holds the running root filesystem"
+        );
+        let sdb = [("sdb".to_string(), (8, 16)), ("sdb1".to_string(), (8, 17))];
+        assert_eq!(
+            find_conflict(&sdb, &mounts).unwrap(),
+            "sdb1 is mounted at /media/my card"
+        );
+        assert_eq!(
+            find_conflict(&[("sdc".to_string(), (8, 32))], &mounts),
+            None
+        );
+    }
+
+    #[test]
+    fn test_related_devices() {
+        // sdz with a partition holding an LVM volume
+        let dir = Path::new("test_related_devices");
+        let disk = dir.join("sdz");
+        let partition = disk.join("sdz1");
+        let volume = partition.join("holders").join("dm-9");
+        std::fs::create_dir_all(&volume).unwrap();
+        std::fs::create_dir_all(disk.join("queue")).unwrap();
+        std::fs::write(disk.join("dev"), "8:240\n").unwrap();
+        std::fs::write(partition.join("dev"), "8:241\n").unwrap();
+        std::fs::write(partition.join("partition"), "1\n").unwrap();
+        std::fs::write(volume.join("dev"), "253:9\n").unwrap();
+
+        let mut related = related_devices(&disk);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        related.sort();
+        assert_eq!(
+            related,
+            vec![
+                ("dm-9".to_string(), (253, 9)),
+                ("sdz".to_string(), (8, 240)),
+                ("sdz1".to_string(), (8, 241)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,698 @@
+use std::{
+    fmt::Display,
+    sync::{
+        mpsc::{Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
+
+use crate::{
+    checkpoint::Checkpointer,
+    device,
+    direct::{AlignedBuffer, DirectFile},
+    discard::Discarder,
+    error::DdsError,
+    events::{draw_target, set_format, tag_output, Event, ProgressEvents},
+    input::Input,
+    lock::open_output,
+    notice,
+    pool::{BufferPool, PooledBuffer},
+    reader::FillReader,
+    report::DiffReport,
+    size::check_sizes,
+    stats::{Stats, Summary},
+    utils::{output_progress_style, validate_paths, WriteJob},
+    verify::verify_with,
+    Dds,
+};
+
+/// How much of the input may be queued for each output. An output that falls this far behind the
+/// input reader is let go of, and reads the rest of the input itself.
+const QUEUE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long an output's progress may stand still before the run stops waiting for it, long enough
+/// for a slow card to sync everything written since its last checkpoint.
+const STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often the outputs are checked on while waiting for them to finish.
+const JOIN_POLL: Duration = Duration::from_millis(50);
+
+/// A block of the input, shared by every output. Its buffer goes back to the pool once every
+/// output is done with it.
+#[derive(Clone)]
+struct Chunk {
+    offset: u64,
+    len: usize,
+    data: Arc<PooledBuffer>,
+}
+
+impl Chunk {
+    fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Work handed from the input reader to each output.
+#[derive(Clone)]
+enum Message {
+    Chunk(Chunk),
+    /// The whole input has been sent, this many bytes of it
+    End(u64),
+}
+
+/// What a run did to one output.
+#[derive(Debug)]
+pub struct Outcome {
+    pub summary: Summary,
+    /// With --dry-run, where the output differs from the input
+    pub report: Option<DiffReport>,
+}
+
+/// How every output of a run went.
+#[derive(Debug, Default)]
+pub struct OutputReport {
+    pub outputs: Vec<(String, Result<Outcome, DdsError>)>,
+}
+
+impl OutputReport {
+    /// How many outputs could not be written.
+    pub fn failed(&self) -> usize {
+        self.outputs
+            .iter()
+            .filter(|(_, outcome)| outcome.is_err())
+            .count()
+    }
+}
+
+impl Display for OutputReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} of {} outputs succeeded",
+            self.outputs.len() - self.failed(),
+            self.outputs.len()
+        )?;
+        for (output, outcome) in &self.outputs {
+            match outcome {
+                Ok(Outcome {
+                    report: Some(report),
+                    ..
+                }) => writeln!(
+                    f,
+                    "  {}: {}",
+                    output,
+                    report.to_string().lines().next().unwrap_or_default()
+                )?,
+                Ok(Outcome { summary, .. }) => writeln!(
+                    f,
+                    "  {}: wrote {} in {} jobs, {:.2}% changed, took {:.2}s",
+                    output,
+                    HumanBytes(summary.bytes_written),
+                    summary.jobs,
+                    summary.percent_changed,
+                    summary.duration_secs
+                )?,
+                // the full error is only a line for the report, the rest of it is the detail
+                Err(e) => writeln!(
+                    f,
+                    "  {}: failed, {}",
+                    output,
+                    e.to_string().lines().next().unwrap_or_default()
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the input once, sending every block of it to each output that is keeping up.
+///
+/// The reader never waits on an output. One whose queue is full is let go of, and carries on from
+/// its own reader, so a slow card doesn't hold back the rest. If the input can't be read, every
+/// output is let go of, and finds out for itself.
+fn read_input(
+    cfg: &Dds,
+    input: Input,
+    pool: Arc<BufferPool>,
+    mut outputs: Vec<SyncSender<Message>>,
+) {
+    let send_all = |outputs: &mut Vec<SyncSender<Message>>, message: &Message| {
+        outputs.retain(|sender| match sender.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+        });
+    };
+
+    let mut reader = FillReader::new(input, 0);
+    loop {
+        let offset = reader.offset();
+        let mut data = pool.take();
+        let len = match reader.fill(&mut data) {
+            Ok(len) => len,
+            Err(e) => {
+                let e = DdsError::io("reading from", &cfg.input, Some(offset), e);
+                notice!("{}, each output reads the rest of the input itself", e);
+                return;
+            }
+        };
+        if len == 0 {
+            break;
+        }
+
+        let chunk = Chunk {
+            offset,
+            len,
+            data: Arc::new(data),
+        };
+        send_all(&mut outputs, &Message::Chunk(chunk));
+        if outputs.is_empty() {
+            return;
+        }
+    }
+
+    send_all(&mut outputs, &Message::End(reader.offset()));
+}
+
+/// Everything one output keeps track of while it is brought in line with the input.
+struct Output<'a> {
+    cfg: &'a Dds,
+    o_reader: FillReader<DirectFile>,
+    o_buffer: AlignedBuffer,
+    checkpointer: Option<Checkpointer>,
+    discarder: Option<Discarder>,
+    report: DiffReport,
+    stats: Stats,
+    pb: ProgressBar,
+    progress: ProgressEvents,
+}
+
+impl Output<'_> {
+    /// Compare a block of the input against the output, writing it where they differ.
+    fn compare(&mut self, input: &[u8], offset: u64) -> Result<(), DdsError> {
+        let cfg = self.cfg;
+
+        let reading = Instant::now();
+        let o_bytes_read = self
+            .o_reader
+            .fill(&mut self.o_buffer[..input.len()])
+            .map_err(|e| DdsError::io("reading from", cfg.output(), Some(offset), e))?;
+        self.stats.read_time += reading.elapsed();
+        let bytes_read = input.len().min(o_bytes_read);
+
+        let mut written = 0;
+        if let Some(job) = WriteJob::diff(cfg, input, &self.o_buffer, bytes_read, offset as usize) {
+            if cfg.dry_run {
+                self.report.record(&job);
+            } else {
+                let (job_offset, blocks) = (job.offset as u64, job.len());
+                let o_file = self.o_reader.get_mut();
+                let writing = Instant::now();
+                written = match &mut self.discarder {
+                    Some(discarder) => job.write_discarding(o_file, discarder)?,
+                    None => job.write(o_file)?,
+                };
+                self.stats.wrote(blocks, written, writing.elapsed());
+                Event::Write {
+                    offset: job_offset,
+                    len: written,
+                    blocks,
+                }
+                .emit();
+            }
+        }
+
+        let done = offset + bytes_read as u64;
+        self.stats.bytes_compared += bytes_read as u64;
+        self.pb.set_position(done);
+        self.progress.update(done);
+
+        if let Some(checkpointer) = &mut self.checkpointer {
+            checkpointer.update(self.o_reader.get_ref().as_file(), written, done);
+        }
+        Ok(())
+    }
+
+    /// Read the input from `offset` on with a reader of its own, once the shared one has let go of
+    /// this output. Returns the length of the input.
+    fn catch_up(&mut self, offset: u64, o_file_size: u64) -> Result<u64, DdsError> {
+        let cfg = self.cfg;
+        let mut input = Input::open(&cfg.input, cfg.direct)?;
+        input
+            .skip_to(offset)
+            .map_err(|e| DdsError::io("reading from", &cfg.input, Some(offset), e))?;
+
+        let mut reader = FillReader::new(input, offset);
+        let mut i_buffer = AlignedBuffer::new(cfg.read_block);
+        loop {
+            let offset = reader.offset();
+            let len = reader
+                .fill(&mut i_buffer)
+                .map_err(|e| DdsError::io("reading from", &cfg.input, Some(offset), e))?;
+            if len == 0 {
+                return Ok(offset);
+            }
+            if offset < o_file_size {
+                self.compare(&i_buffer[..len], offset)?;
+            }
+        }
+    }
+}
+
+/// An output's thread, and when its progress last moved.
+struct Running {
+    thread: JoinHandle<Result<Outcome, DdsError>>,
+    pb: ProgressBar,
+    position: u64,
+    moved: Instant,
+}
+
+impl Running {
+    fn new(thread: JoinHandle<Result<Outcome, DdsError>>, pb: ProgressBar) -> Running {
+        Running {
+            thread,
+            position: pb.position(),
+            pb,
+            moved: Instant::now(),
+        }
+    }
+
+    /// Whether the output's progress has stood still for `timeout`.
+    fn stalled(&mut self, timeout: Duration) -> bool {
+        let position = self.pb.position();
+        if position != self.position {
+            self.position = position;
+            self.moved = Instant::now();
+        }
+        self.moved.elapsed() >= timeout
+    }
+}
+
+/// Wait for every output to finish, giving up on any whose progress stands still for `timeout` so
+/// one wedged in a write can't keep the rest from being reported.
+fn join_outputs(
+    threads: Vec<(String, Result<Running, DdsError>)>,
+    timeout: Duration,
+) -> Vec<(String, Result<Outcome, DdsError>)> {
+    let mut joined = Vec::new();
+    let mut pending = Vec::new();
+    for (i, (output, thread)) in threads.into_iter().enumerate() {
+        match thread {
+            Ok(running) => pending.push((i, output, running)),
+            Err(e) => joined.push((i, output, Err(e))),
+        }
+    }
+
+    while !pending.is_empty() {
+        for (i, output, mut running) in std::mem::take(&mut pending) {
+            if running.thread.is_finished() {
+                let outcome = running
+                    .thread
+                    .join()
+                    .unwrap_or_else(|_| Err(DdsError::thread("output", "panicked")));
+                joined.push((i, output, outcome));
+            } else if running.stalled(timeout) {
+                // the thread is left to itself, it can't be stopped in the middle of a write
+                running.pb.abandon_with_message("failed, stalled");
+                let stalled = DdsError::Stalled {
+                    output: output.clone(),
+                    secs: timeout.as_secs(),
+                };
+                joined.push((i, output, Err(stalled)));
+            } else {
+                pending.push((i, output, running));
+            }
+        }
+        if !pending.is_empty() {
+            std::thread::sleep(JOIN_POLL);
+        }
+    }
+
+    joined.sort_by_key(|(i, ..)| *i);
+    joined
+        .into_iter()
+        .map(|(_, output, outcome)| (output, outcome))
+        .collect()
+}
+
+/// Bring one output in line with the input as its blocks arrive.
+fn restore(
+    cfg: &Dds,
+    input_size: Option<u64>,
+    chunks: Receiver<Message>,
+    pb: ProgressBar,
+) -> Result<Outcome, DdsError> {
+    let started = Instant::now();
+    validate_paths(cfg)?;
+
+    let o_file = open_output(cfg.output(), cfg.writes_output(), cfg.direct)?;
+    let o_file_size = device::size(o_file.as_file())
+        .map_err(|e| DdsError::io("finding the size of", cfg.output(), None, e))?;
+    check_sizes(cfg, input_size, o_file_size)?;
+    let size = match input_size {
+        Some(size) if cfg.zero_tail => size.max(o_file_size),
+        Some(size) => size,
+        None => o_file_size,
+    };
+    pb.set_length(size);
+    Event::Start {
+        input: &cfg.input,
+        output: cfg.output(),
+        size,
+        threaded: false,
+        dry_run: cfg.dry_run,
+    }
+    .emit();
+
+    let checkpointer = match cfg.writes_output() {
+        true => Some(Checkpointer::new(cfg, 0)?),
+        false => None,
+    };
+    let discarder = match cfg.discard {
+        true => Some(
+            Discarder::new(o_file.as_file())
+                .map_err(|e| DdsError::io("reading metadata of", cfg.output(), None, e))?,
+        ),
+        false => None,
+    };
+
+    let mut output = Output {
+        cfg,
+        o_reader: FillReader::new(o_file, 0),
+        o_buffer: AlignedBuffer::new(cfg.read_block),
+        checkpointer,
+        discarder,
+        report: DiffReport::default(),
+        stats: Stats::default(),
+        pb,
+        progress: ProgressEvents::new(size),
+    };
+
+    let mut input_len = None;
+    let mut next = 0;
+    while let Ok(message) = chunks.recv() {
+        match message {
+            Message::Chunk(chunk) => {
+                next = chunk.offset + chunk.len as u64;
+                // past the end of the output, the rest of the input is only counted
+                if chunk.offset < o_file_size {
+                    output.compare(chunk.data(), chunk.offset)?;
+                }
+            }
+            Message::End(len) => {
+                input_len = Some(len);
+                break;
+            }
+        }
+    }
+    // the reader let go of this output before the end, so it reads the rest itself
+    let input_len = match input_len {
+        Some(len) => len,
+        None => output.catch_up(next, o_file_size)?,
+    };
+
+    // the sizes of a compressed input are only known now, the rest were checked up front
+    let mismatch = DdsError::SizeMismatch {
+        input: input_len,
+        output: o_file_size,
+    };
+    if input_len > o_file_size && !cfg.allow_truncate {
+        return Err(mismatch);
+    }
+    if input_len < o_file_size {
+        if cfg.zero_tail {
+            let zeros = AlignedBuffer::new(cfg.read_block);
+            for offset in (input_len..o_file_size).step_by(cfg.read_block) {
+                let len = (o_file_size - offset).min(cfg.read_block as u64) as usize;
+                output.compare(&zeros[..len], offset)?;
+            }
+        } else if !cfg.ignore_tail {
+            return Err(mismatch);
+        }
+    }
+
+    let Output {
+        o_reader,
+        checkpointer,
+        discarder,
+        report,
+        stats,
+        pb,
+        ..
+    } = output;
+    pb.finish();
+
+    // the output is only done once the writes have reached the device
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.flush(o_reader.get_ref().as_file(), cfg.output(), pb.clone())?;
+        checkpointer.finish();
+    }
+    if let Some(discarder) = discarder {
+        discarder.report();
+    }
+    drop(o_reader);
+
+    if cfg.verify {
+        pb.set_style(output_progress_style());
+        let mismatches = verify_with(cfg, pb.clone())?;
+        Event::Verify {
+            passed: mismatches.is_empty(),
+            mismatched_bytes: mismatches.bytes,
+        }
+        .emit();
+        if !mismatches.is_empty() {
+            return Err(DdsError::VerifyFailed(mismatches));
+        }
+        pb.finish_with_message("verified");
+    }
+
+    let summary = stats.summary(started.elapsed());
+    Event::Finish {
+        summary: summary.clone(),
+    }
+    .emit();
+    Ok(Outcome {
+        summary,
+        report: cfg.dry_run.then_some(report),
+    })
+}
+
+/// Write one input to every output given, reading the input only once.
+///
+/// Every output gets its own thread and progress bar, so one that fails is reported at the end
+/// without stopping the rest. With `--output-format json` each output emits the events of a run of
+/// its own, tagged with the output, followed by an `output` event for each once they all finish.
+pub fn controller(cfg: Dds) -> Result<(), DdsError> {
+    set_format(cfg.output_format);
+    cfg.validate()?;
+
+    let input = Input::open(&cfg.input, cfg.direct)?;
+    let input_size = input.size();
+    let depth = (QUEUE_SIZE / cfg.read_block).max(1);
+    let m_pb = MultiProgress::with_draw_target(draw_target());
+
+    let mut senders = Vec::new();
+    let mut threads = Vec::new();
+    for output in &cfg.outputs {
+        let (chunks_tx, chunks_rx) = std::sync::mpsc::sync_channel(depth);
+        let pb = m_pb.add(ProgressBar::new(0));
+        pb.set_style(output_progress_style());
+        pb.set_prefix(output.clone());
+
+        // not scoped, so an output wedged in a write can be left behind
+        let o_cfg = cfg.for_output(output);
+        let o_pb = pb.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("output {}", output))
+            .spawn(move || {
+                tag_output(o_cfg.output());
+                let result = restore(&o_cfg, input_size, chunks_rx, o_pb.clone());
+                if let Err(e) = &result {
+                    let reason = e.to_string();
+                    o_pb.abandon_with_message(format!(
+                        "failed, {}",
+                        reason.lines().next().unwrap_or_default()
+                    ));
+                }
+                result
+            })
+            .map_err(|e| DdsError::thread("output", e));
+        if thread.is_ok() {
+            senders.push(chunks_tx);
+        }
+        threads.push((
+            output.clone(),
+            thread.map(|thread| Running::new(thread, pb)),
+        ));
+    }
+
+    read_input(&cfg, input, BufferPool::new(cfg.read_block), senders);
+    let report = OutputReport {
+        outputs: join_outputs(threads, STALL_TIMEOUT),
+    };
+
+    notice!("{}", report.to_string().trim_end());
+    for (output, outcome) in &report.outputs {
+        Event::Output {
+            output,
+            error: outcome.as_ref().err().map(|e| e.to_string()),
+            summary: outcome.as_ref().ok().map(|outcome| outcome.summary.clone()),
+        }
+        .emit();
+    }
+
+    match report.failed() {
+        0 => Ok(()),
+        failed => Err(DdsError::OutputsFailed {
+            failed,
+            total: report.outputs.len(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join_outputs, read_input, restore, Message, Outcome, OutputReport, Running};
+    use crate::{error::DdsError, input::Input, pool::BufferPool, stats::Stats, Dds};
+    use indicatif::ProgressBar;
+    use std::{sync::mpsc::sync_channel, time::Duration};
+
+    #[test]
+    fn test_output_report() {
+        let mut stats = Stats {
+            bytes_compared: 4096,
+            ..Default::default()
+        };
+        stats.wrote(2, 1024, Duration::from_millis(10));
+        let report = OutputReport {
+            outputs: vec![
+                (
+                    "/dev/sdb".to_string(),
+                    Ok(Outcome {
+                        summary: stats.summary(Duration::from_secs(2)),
+                        report: None,
+                    }),
+                ),
+                ("/dev/sdc".to_string(), Err(DdsError::Aborted)),
+            ],
+        };
+
+        assert_eq!(report.failed(), 1);
+        assert_eq!(
+            report.to_string(),
+            "1 of 2 outputs succeeded\n  \
+             /dev/sdb: wrote 1.00 KiB in 1 jobs, 25.00% changed, took 2.00s\n  \
+             /dev/sdc: failed, Aborting\n"
+        );
+    }
+
+    #[test]
+    fn test_full_queue_is_let_go() {
+        let path = "test_full_queue_is_let_go.bin";
+        std::fs::write(path, vec![1u8; 64 * 1024]).unwrap();
+        let cfg = Dds {
+            input: path.to_string(),
+            read_block: 4096,
+            ..Default::default()
+        };
+
+        // room for the whole input, so it never falls behind
+        let (fast_tx, fast_rx) = sync_channel(17);
+        // not read from until the input is done, so it only takes the first block
+        let (slow_tx, slow_rx) = sync_channel(1);
+        let fast = std::thread::spawn(move || {
+            fast_rx
+                .iter()
+                .filter_map(|message| match message {
+                    Message::End(len) => Some(len),
+                    Message::Chunk(_) => None,
+                })
+                .next()
+        });
+
+        read_input(
+            &cfg,
+            Input::open(path, false).unwrap(),
+            BufferPool::new(4096),
+            vec![fast_tx, slow_tx],
+        );
+        let fast_len = fast.join().unwrap();
+        let slow: Vec<_> = slow_rx.iter().collect();
+        std::fs::remove_file(path).unwrap();
+
+        // the rest of the outputs still get the whole input
+        assert_eq!(fast_len, Some(64 * 1024));
+        assert!(matches!(slow.as_slice(), [Message::Chunk(chunk)] if chunk.offset == 0));
+    }
+
+    #[test]
+    fn test_let_go_output_catches_up() {
+        let (input, output) = (
+            "test_let_go_output_catches_up.bin",
+            "test_let_go_output_catches_up.bin.copy",
+        );
+        std::fs::write(input, vec![1u8; 64 * 1024]).unwrap();
+        std::fs::write(output, vec![0u8; 64 * 1024]).unwrap();
+        let cfg = Dds {
+            input: input.to_string(),
+            outputs: vec![output.to_string()],
+            read_block: 4096,
+            dry_run: true,
+            yes: true,
+            ..Default::default()
+        };
+
+        // the queue holds two blocks, the output reads the other fourteen itself
+        let (chunks_tx, chunks_rx) = sync_channel(2);
+        read_input(
+            &cfg,
+            Input::open(input, false).unwrap(),
+            BufferPool::new(4096),
+            vec![chunks_tx],
+        );
+        let outcome = restore(&cfg, Some(64 * 1024), chunks_rx, ProgressBar::hidden());
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+
+        let report = outcome.unwrap().report.unwrap();
+        assert_eq!(report.ranges, vec![0..64 * 1024]);
+        assert_eq!(report.bytes, 64 * 1024);
+    }
+
+    #[test]
+    fn test_wedged_output_is_reported() {
+        let (wedge_tx, wedge_rx) = sync_channel::<()>(0);
+        let wedged = std::thread::spawn(move || {
+            let _ = wedge_rx.recv();
+            Err(DdsError::Aborted)
+        });
+        let finished = std::thread::spawn(|| Err(DdsError::Aborted));
+
+        let outputs = join_outputs(
+            vec![
+                (
+                    "wedged".to_string(),
+                    Ok(Running::new(wedged, ProgressBar::hidden())),
+                ),
+                (
+                    "finished".to_string(),
+                    Ok(Running::new(finished, ProgressBar::hidden())),
+                ),
+            ],
+            Duration::from_millis(100),
+        );
+        drop(wedge_tx);
+
+        assert!(matches!(
+            &outputs[0],
+            (output, Err(DdsError::Stalled { .. })) if output == "wedged"
+        ));
+        assert!(matches!(
+            &outputs[1],
+            (output, Err(DdsError::Aborted)) if output == "finished"
+        ));
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    device, direct::DirectFile, error::DdsError, events::draw_target, lock::open_output, notice,
+    utils::progress_style, utils::WriteJob, ApplyArgs,
+};
+
+/// Every patch starts with these bytes.
+const PATCH_MAGIC: &[u8; 8] = b"DDSPATCH";
+/// Bumped whenever the layout of a patch changes.
+const PATCH_VERSION: u32 = 1;
+/// The offset of the record that marks the end of a patch, so a truncated patch can be detected.
+const END_OF_RECORDS: u64 = u64::MAX;
+/// Where the input size and checksum live, they are filled in once the whole input has been read.
+const DIGEST_OFFSET: u64 = (PATCH_MAGIC.len() + 4) as u64;
+/// How many bytes come before the first record.
+const HEADER_SIZE: u64 = DIGEST_OFFSET + 8 + 32;
+/// How many bytes come before the data of a record.
+pub(crate) const RECORD_HEADER_SIZE: u64 = 16;
+/// How much of the output is hashed at once when checking an applied patch.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The start of a patch, describing the input it recreates.
+///
+/// A patch is laid out as
+/// - the magic bytes `DDSPATCH` and the format version as a little endian `u32`
+/// - the size of the input as a little endian `u64`, and the sha256 of the input
+/// - a record for every block that differs: its offset and length as little endian `u64`s,
+///   followed by the data
+/// - a final record with an offset of `u64::MAX` and no data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchHeader {
+    pub input_size: u64,
+    pub input_sha256: [u8; 32],
+}
+
+/// The size and checksum of the input, built up as the compare loop reads through it.
+#[derive(Debug, Default, Clone)]
+pub struct InputDigest {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl InputDigest {
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+    }
+
+    fn finish(self) -> PatchHeader {
+        PatchHeader {
+            input_size: self.size,
+            input_sha256: self.hasher.finalize().into(),
+        }
+    }
+}
+
+/// Records the blocks the compare loop finds into a patch, rather than writing them to the output.
+pub struct PatchWriter<W: Write + Seek> {
+    patch: BufWriter<W>,
+}
+
+impl PatchWriter<File> {
+    pub fn create(path: &str, input_size: u64) -> Result<PatchWriter<File>, DdsError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| DdsError::io("creating", path, None, e))?;
+        PatchWriter::new(file, input_size).map_err(|e| DdsError::io("writing to", path, Some(0), e))
+    }
+}
+
+impl<W: Write + Seek> PatchWriter<W> {
+    /// Start a patch, leaving space in the header for the checksum of the input.
+    ///
+    /// The size of the input is filled in by `finish`, giving it up front if it is already known
+    /// lets the records of a patch that was never finished be read back.
+    pub fn new(patch: W, input_size: u64) -> std::io::Result<PatchWriter<W>> {
+        let mut patch = BufWriter::new(patch);
+        patch.write_all(PATCH_MAGIC)?;
+        patch.write_all(&PATCH_VERSION.to_le_bytes())?;
+        patch.write_all(&input_size.to_le_bytes())?;
+        patch.write_all(&[0u8; 32])?;
+        Ok(PatchWriter { patch })
+    }
+
+    /// Add every block of a job to the patch.
+    pub fn record(&mut self, job: &WriteJob) -> std::io::Result<()> {
+        self.record_chunks(job.chunks())
+    }
+
+    /// Add a record for each `(offset, data)` to the patch.
+    pub fn record_chunks<'a>(
+        &mut self,
+        chunks: impl Iterator<Item = (u64, &'a [u8])>,
+    ) -> std::io::Result<()> {
+        for (offset, data) in chunks {
+            self.patch.write_all(&offset.to_le_bytes())?;
+            self.patch.write_all(&(data.len() as u64).to_le_bytes())?;
+            self.patch.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// The file the patch is written to.
+    pub fn get_ref(&self) -> &W {
+        self.patch.get_ref()
+    }
+
+    /// Hand every record so far to the OS, so they survive `dds` being killed.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.patch.flush()
+    }
+
+    /// Close off the records, then go back and fill in the header now the input has been read.
+    pub fn finish(mut self, digest: InputDigest) -> std::io::Result<W> {
+        let header = digest.finish();
+
+        self.patch.write_all(&END_OF_RECORDS.to_le_bytes())?;
+        self.patch.write_all(&0u64.to_le_bytes())?;
+        self.patch.seek(SeekFrom::Start(DIGEST_OFFSET))?;
+        self.patch.write_all(&header.input_size.to_le_bytes())?;
+        self.patch.write_all(&header.input_sha256)?;
+
+        self.patch.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// Reads the header and records of a patch back.
+pub struct PatchReader<R: Read> {
+    patch: R,
+    pub header: PatchHeader,
+    finished: bool,
+    position: u64,
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// A patch that ends part way through is reported as truncated, keeping the `UnexpectedEof` kind
+/// so an interrupted undo log can be told apart from a corrupt one.
+fn truncated(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => {
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the patch is truncated")
+        }
+        _ => e,
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl<R: Read> PatchReader<R> {
+    pub fn new(mut patch: R) -> std::io::Result<PatchReader<R>> {
+        let mut magic = [0u8; 8];
+        patch.read_exact(&mut magic)?;
+        if &magic != PATCH_MAGIC {
+            return Err(invalid("not a dds patch"));
+        }
+
+        let mut version = [0u8; 4];
+        patch.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != PATCH_VERSION {
+            return Err(invalid(&format!(
+                "unsupported patch version {}, expected {}",
+                version, PATCH_VERSION
+            )));
+        }
+
+        let input_size = read_u64(&mut patch)?;
+        let mut input_sha256 = [0u8; 32];
+        patch.read_exact(&mut input_sha256)?;
+
+        Ok(PatchReader {
+            patch,
+            header: PatchHeader {
+                input_size,
+                input_sha256,
+            },
+            finished: false,
+            position: HEADER_SIZE,
+        })
+    }
+
+    /// Where in the patch the next record starts.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether the record marking the end of the patch has been read.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn into_inner(self) -> R {
+        self.patch
+    }
+
+    /// Read the offset and length of the next record, leaving its data to be read.
+    fn next_header(&mut self) -> std::io::Result<Option<(u64, u64)>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let offset = read_u64(&mut self.patch).map_err(truncated)?;
+        let len = read_u64(&mut self.patch).map_err(truncated)?;
+        if offset == END_OF_RECORDS {
+            self.finished = true;
+            return Ok(None);
+        }
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > self.header.input_size)
+        {
+            return Err(invalid("a record lies outside of the input"));
+        }
+
+        self.position += RECORD_HEADER_SIZE;
+        Ok(Some((offset, len)))
+    }
+
+    /// Read the next record into `data`, returning its offset, or `None` once every record is read.
+    pub fn next_record(&mut self, data: &mut Vec<u8>) -> std::io::Result<Option<u64>> {
+        let (offset, len) = match self.next_header()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        data.clear();
+        (&mut self.patch)
+            .take(len)
+            .read_to_end(data)
+            .map_err(truncated)?;
+        if data.len() as u64 != len {
+            return Err(truncated(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.position += len;
+        Ok(Some(offset))
+    }
+
+    /// Move past the next record without keeping its data, returning its offset and length.
+    pub fn skip_record(&mut self) -> std::io::Result<Option<(u64, u64)>> {
+        let (offset, len) = match self.next_header()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let skipped = std::io::copy(&mut (&mut self.patch).take(len), &mut std::io::sink())
+            .map_err(truncated)?;
+        if skipped != len {
+            return Err(truncated(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.position += len;
+        Ok(Some((offset, len)))
+    }
+}
+
+/// Write every record of a patch onto the output.
+pub fn apply(args: &ApplyArgs) -> Result<(), DdsError> {
+    let patch_err = |e: std::io::Error| DdsError::Patch {
+        path: args.patch.clone(),
+        reason: e.to_string(),
+    };
+
+    let patch =
+        File::open(&args.patch).map_err(|e| DdsError::io("opening", &args.patch, None, e))?;
+    let mut patch = PatchReader::new(BufReader::new(patch)).map_err(patch_err)?;
+
+    // a patch that's truncated or corrupt part way through must not leave a half patched output,
+    // so every record is checked before the output is touched
+    if patch.header.input_sha256 == [0u8; 32] {
+        return Err(patch_err(invalid("the patch was never finished")));
+    }
+    while patch.skip_record().map_err(patch_err)?.is_some() {}
+    let mut patch = patch.into_inner();
+    patch
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| DdsError::io("seeking in", &args.patch, Some(0), e))?;
+    let mut patch = PatchReader::new(patch).map_err(patch_err)?;
+
+    let mut o_file = open_output(&args.output, true, false)?;
+
+    // a block device can't grow to fit the patch
+    let o_file_size = device::size(o_file.as_file())
+        .map_err(|e| DdsError::io("finding the size of", &args.output, None, e))?;
+    let is_block_device = device::is_block_device(o_file.as_file())
+        .map_err(|e| DdsError::io("reading metadata of", &args.output, None, e))?;
+    if is_block_device && o_file_size < patch.header.input_size {
+        return Err(DdsError::SizeMismatch {
+            input: patch.header.input_size,
+            output: o_file_size,
+        });
+    }
+
+    let pb = ProgressBar::with_draw_target(Some(patch.header.input_size), draw_target());
+    pb.set_style(progress_style());
+
+    let mut data = Vec::new();
+    while let Some(offset) = patch.next_record(&mut data).map_err(patch_err)? {
+        let write_err = |source| DdsError::Write {
+            offset,
+            len: data.len(),
+            source,
+        };
+        o_file.seek(SeekFrom::Start(offset)).map_err(write_err)?;
+        o_file.write_all(&data).map_err(write_err)?;
+        pb.set_position(offset + data.len() as u64);
+    }
+    o_file
+        .as_file()
+        .sync_all()
+        .map_err(|e| DdsError::io("syncing", &args.output, None, e))?;
+    pb.finish_with_message("Complete");
+
+    if args.verify {
+        notice!("Verifying {}", &args.output);
+        if hash_output(&mut o_file, &args.output, patch.header.input_size)?
+            != patch.header.input_sha256
+        {
+            return Err(DdsError::Patch {
+                path: args.patch.clone(),
+                reason: "the patched output does not match the checksum of the input".to_string(),
+            });
+        }
+        notice!("Verification passed");
+    }
+
+    Ok(())
+}
+
+/// The sha256 of the first `size` bytes of the output.
+pub(crate) fn hash_output(
+    o_file: &mut DirectFile,
+    path: &str,
+    size: u64,
+) -> Result<[u8; 32], DdsError> {
+    o_file
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| DdsError::io("seeking in", path, Some(0), e))?;
+
+    let mut hasher = Sha256::new();
+    let mut reader = BufReader::with_capacity(HASH_BUFFER_SIZE, o_file.take(size));
+    std::io::copy(&mut reader, &mut hasher)
+        .map_err(|e| DdsError::io("reading from", path, None, e))?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{InputDigest, PatchReader, PatchWriter};
+    use crate::utils::WriteJob;
+
+    fn patch_of(input: &[u8], output: &[u8]) -> Vec<u8> {
+        let job = WriteJob::break_into_blocks(input.to_vec(), output, input.len(), 0, 512);
+        let mut digest = InputDigest::default();
+        digest.update(input);
+
+        let mut writer = PatchWriter::new(Cursor::new(Vec::new()), 0).unwrap();
+        writer.record(&job).unwrap();
+        writer.finish(digest).unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let input = vec![1u8; 4096];
+        let mut output = input.clone();
+        output[10] = 0;
+        output[3000] = 0;
+
+        let patch = patch_of(&input, &output);
+        let mut reader = PatchReader::new(Cursor::new(patch)).unwrap();
+        assert_eq!(reader.header.input_size, 4096);
+
+        let mut data = Vec::new();
+        let mut records = Vec::new();
+        while let Some(offset) = reader.next_record(&mut data).unwrap() {
+            output[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+            records.push((offset, data.len()));
+        }
+
+        assert_eq!(records, vec![(0, 512), (2560, 512)]);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_patch_rejects_truncation() {
+        let input = vec![1u8; 4096];
+        let mut patch = patch_of(&input, &[0u8; 4096]);
+        patch.truncate(patch.len() - 100);
+
+        let mut reader = PatchReader::new(Cursor::new(patch)).unwrap();
+        let mut data = Vec::new();
+        let err = loop {
+            match reader.next_record(&mut data) {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("a truncated patch was read to the end"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.to_string(), "the patch is truncated");
+    }
+
+    #[test]
+    fn test_patch_skip_records() {
+        let input = vec![1u8; 4096];
+        let mut output = input.clone();
+        output[10] = 0;
+        output[3000] = 0;
+
+        let patch = patch_of(&input, &output);
+        let mut reader = PatchReader::new(Cursor::new(patch.clone())).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.skip_record().unwrap() {
+            records.push((reader.position(), record));
+        }
+
+        assert!(reader.is_finished());
+        assert_eq!(
+            records,
+            vec![(52 + 16 + 512, (0, 512)), (52 + 32 + 1024, (2560, 512))]
+        );
+        assert_eq!(reader.position() + 16, patch.len() as u64);
+    }
+
+    #[test]
+    fn test_unfinished_patch_is_readable() {
+        let input = vec![1u8; 4096];
+        let job = WriteJob::break_into_blocks(input.clone(), &[0u8; 4096], 4096, 0, 4096);
+
+        let mut writer = PatchWriter::new(Cursor::new(Vec::new()), 4096).unwrap();
+        writer.record(&job).unwrap();
+        writer.flush().unwrap();
+        let patch = writer.patch.into_inner().unwrap().into_inner();
+
+        let mut reader = PatchReader::new(Cursor::new(patch)).unwrap();
+        let mut data = Vec::new();
+        assert_eq!(reader.next_record(&mut data).unwrap(), Some(0));
+        assert_eq!(data, input);
+        let err = reader.next_record(&mut data).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(!reader.is_finished());
+    }
+
+    #[test]
+    fn test_patch_rejects_other_files() {
+        let err = PatchReader::new(Cursor::new(b"DDSPATCX\x01\x00\x00\x00".to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "not a dds patch");
+
+        let err = PatchReader::new(Cursor::new(b"DDSPATCH\x02\x00\x00\x00".to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "unsupported patch version 2, expected 1");
+    }
+}
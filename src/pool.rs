@@ -0,0 +1,107 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use crate::direct::AlignedBuffer;
+
+/// A free list of equally sized buffers, so the stages of a pipeline can hand blocks between
+/// each other without allocating a new buffer for every one.
+///
+/// Taking a buffer never blocks, a new one is allocated when none are free. The bounded channels
+/// between stages already limit how many blocks are in flight, so the pool only ever grows to
+/// that many buffers.
+pub struct BufferPool {
+    size: usize,
+    free: Mutex<Vec<AlignedBuffer>>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            size,
+            free: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Take a buffer from the pool, which goes back into it once dropped.
+    ///
+    /// A reused buffer still holds whatever was last read into it.
+    pub fn take(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self
+            .free
+            .lock()
+            .expect("no thread panics while holding the pool")
+            .pop()
+            .unwrap_or_else(|| AlignedBuffer::new(self.size));
+        PooledBuffer {
+            buffer: Some(buffer),
+            pool: Arc::clone(self),
+        }
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn free(&self) -> usize {
+        self.free
+            .lock()
+            .expect("no thread panics while holding the pool")
+            .len()
+    }
+}
+
+/// A buffer borrowed from a `BufferPool`.
+pub struct PooledBuffer {
+    // only `None` while being dropped
+    buffer: Option<AlignedBuffer>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer
+            .as_ref()
+            .expect("the buffer is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer
+            .as_mut()
+            .expect("the buffer is only taken on drop")
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let (Some(buffer), Ok(mut free)) = (self.buffer.take(), self.pool.free.lock()) {
+            free.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new(4096);
+        let mut first = pool.take();
+        first[0] = 7;
+        let second = pool.take();
+        assert_eq!((first.len(), second.len()), (4096, 4096));
+        assert_eq!(pool.free(), 0);
+
+        drop(second);
+        drop(first);
+        assert_eq!(pool.free(), 2);
+
+        // the buffer comes back as it was left
+        let reused = pool.take();
+        assert_eq!(pool.free(), 1);
+        assert_eq!(reused[0], 7);
+    }
+}
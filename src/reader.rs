@@ -0,0 +1,119 @@
+use std::io::Read;
+
+/// Fill as much of `buf` as possible, only returning less than a full buffer at the end of the file.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads whole buffers at a time however the underlying reader splits them up, keeping track of
+/// how far into the file it is.
+///
+/// Pipes, FUSE mounts and some block drivers return short reads, so a single `read` can't be
+/// relied on to line the input and output up.
+#[derive(Debug)]
+pub struct FillReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Read> FillReader<R> {
+    /// Wrap a reader that is already `offset` bytes into its file.
+    pub fn new(inner: R, offset: u64) -> FillReader<R> {
+        FillReader { inner, offset }
+    }
+
+    /// The offset in the file of the next byte to be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Fill `buf`, returning how much was read, which is only less than `buf.len()` at the end
+    /// of the file.
+    pub fn fill(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = read_full(&mut self.inner, buf);
+        // even a failed read may have moved through the file
+        if let Ok(read) = read {
+            self.offset += read as u64;
+        }
+        read
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{read_full, FillReader};
+
+    /// A reader that returns a random number of bytes from every read, and is sometimes
+    /// interrupted.
+    struct ShortReader {
+        inner: Cursor<Vec<u8>>,
+        rng: StdRng,
+    }
+
+    impl ShortReader {
+        fn new(data: Vec<u8>, seed: u64) -> ShortReader {
+            ShortReader {
+                inner: Cursor::new(data),
+                rng: StdRng::seed_from_u64(seed),
+            }
+        }
+    }
+
+    impl Read for ShortReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            if self.rng.gen_ratio(1, 10) {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let len = self.rng.gen_range(1..=buf.len());
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_read_full_fills_buffer() {
+        let mut reader = ShortReader::new((0..100).collect(), 1);
+        let mut buf = [0u8; 64];
+
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 64);
+        assert_eq!(buf[63], 63);
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 36);
+        assert_eq!(buf[35], 99);
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fill_reader_tracks_offset() {
+        let mut reader = FillReader::new(ShortReader::new(vec![1u8; 10_000], 2), 5120);
+        let mut buf = vec![0u8; 4096];
+
+        assert_eq!(reader.fill(&mut buf).unwrap(), 4096);
+        assert_eq!(reader.offset(), 5120 + 4096);
+        assert_eq!(reader.fill(&mut buf).unwrap(), 4096);
+        assert_eq!(reader.fill(&mut buf).unwrap(), 10_000 - 8192);
+        assert_eq!(reader.offset(), 5120 + 10_000);
+    }
+}
@@ -0,0 +1,115 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::utils::WriteJob;
+
+/// A summary of every region that differs between the input and the output, built up from the
+/// `WriteJob`s produced by the compare loop.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DiffReport {
+    /// The total number of bytes that would be written
+    pub bytes: u64,
+    /// The number of blocks that would be written
+    pub blocks: usize,
+    /// Differing regions of the output, with touching regions merged together
+    pub ranges: Vec<Range<u64>>,
+}
+
+impl DiffReport {
+    /// Record every block of a job in the report.
+    pub fn record(&mut self, job: &WriteJob) {
+        for (offset, len) in job.regions() {
+            self.add(offset..offset + len as u64);
+        }
+    }
+
+    /// Record a single differing region, merging it into the previous range if they touch.
+    pub fn add(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        self.bytes += range.end - range.start;
+        self.blocks += 1;
+
+        match self.ranges.last_mut() {
+            Some(last) if last.end >= range.start && range.end >= last.start => {
+                last.start = last.start.min(range.start);
+                last.end = last.end.max(range.end);
+            }
+            _ => self.ranges.push(range),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks == 0
+    }
+}
+
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} bytes differ across {} blocks in {} ranges",
+            self.bytes,
+            self.blocks,
+            self.ranges.len()
+        )?;
+        for range in &self.ranges {
+            writeln!(
+                f,
+                "  [{}..{}] ({} bytes)",
+                range.start,
+                range.end,
+                range.end - range.start
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiffReport;
+    use crate::utils::WriteJob;
+
+    #[test]
+    fn test_report_merges_touching_ranges() {
+        let mut report = DiffReport::default();
+        report.add(0..512);
+        report.add(512..1024);
+        report.add(2048..2560);
+
+        assert_eq!(report.bytes, 1536);
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.ranges, vec![0..1024, 2048..2560]);
+    }
+
+    #[test]
+    fn test_report_ignores_empty_ranges() {
+        let mut report = DiffReport::default();
+        report.add(10..10);
+
+        assert!(report.is_empty());
+        assert!(report.ranges.is_empty());
+    }
+
+    #[test]
+    fn test_report_records_job() {
+        let input = vec![0u8; 1024 * 4];
+        let mut invalid = vec![0u8; 1024 * 4];
+        invalid[0] = 1;
+        invalid[1024] = 1;
+        invalid[1024 * 3] = 1;
+        let job = WriteJob::break_into_blocks(input, &invalid, 1024 * 4, 1024 * 10, 1024);
+
+        let mut report = DiffReport::default();
+        report.record(&job);
+
+        assert_eq!(report.bytes, 1024 * 3);
+        assert_eq!(report.blocks, 3);
+        assert_eq!(
+            report.ranges,
+            vec![1024 * 10..1024 * 12, 1024 * 13..1024 * 14]
+        );
+    }
+}
@@ -1,103 +1,241 @@
-use std::{fmt::Write, fs::OpenOptions, io::Read};
+use std::{
+    io::{Seek, SeekFrom},
+    time::Instant,
+};
 
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use indicatif::ProgressBar;
 
 use crate::{
+    backup::{create_image, resize_image},
+    checkpoint::{start_offset, Checkpointer},
+    device,
+    direct::AlignedBuffer,
+    discard::Discarder,
     error::DdsError,
-    utils::{validate_paths, WriteJob},
-    Dds, BLOCK_SIZE, MIN_BLOCK_SIZE,
+    events::{draw_target, set_format, Event, ProgressEvents},
+    input::{progress_length, Input},
+    lock::open_output,
+    notice,
+    patch::{InputDigest, PatchWriter},
+    pool::BufferPool,
+    reader::FillReader,
+    report::DiffReport,
+    size::{check_ends, reconcile},
+    stats::Stats,
+    undo::UndoLog,
+    utils::{progress_style, validate_paths, WriteJob},
+    verify::check,
+    Dds,
 };
 
-fn __controller(cfg: Dds) {
-    validate_paths(&cfg);
-
-    let mut i_file = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .open(&cfg.input)
-        .unwrap();
-
-    let mut o_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(false)
-        .open(&cfg.output)
-        .unwrap();
-
-    let i_file_size = i_file.metadata().unwrap().len();
-
-    let pb = ProgressBar::new(i_file_size);
-    pb.set_position(0);
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#>-"));
-
-    let mut o_buffer = [0u8; BLOCK_SIZE];
-    let mut read_blocks = 0;
+fn __controller(cfg: Dds) -> Result<(), DdsError> {
+    set_format(cfg.output_format);
+    let started = Instant::now();
+    validate_paths(&cfg)?;
+    cfg.validate()?;
+
+    if cfg.backup && !cfg.dry_run {
+        create_image(&cfg)?;
+    }
+
+    let mut i_file = Input::open(&cfg.input, cfg.direct)?;
+
+    // a dry run or export never opens the output for writing, so it can't wear the device
+    let mut o_file = open_output(cfg.output(), cfg.writes_output(), cfg.direct)?;
+    // the image is only resized once no other run can be writing to it
+    if cfg.backup && !cfg.dry_run {
+        resize_image(&cfg, o_file.as_file())?;
+    }
+
+    // make sure the input and output line up, before anything is written
+    let o_file_size = device::size(o_file.as_file())
+        .map_err(|e| DdsError::io("finding the size of", cfg.output(), None, e))?;
+    reconcile(&cfg, &mut i_file, o_file_size)?;
+    let i_file_size = progress_length(&i_file, o_file.as_file(), cfg.output())?;
+
+    // pick up where a previous run left off
+    let start = start_offset(&cfg, cfg.read_block as u64)?;
+    i_file
+        .skip_to(start)
+        .map_err(|e| DdsError::io("seeking in", &cfg.input, Some(start), e))?;
+    o_file
+        .seek(SeekFrom::Start(start))
+        .map_err(|e| DdsError::io("seeking in", cfg.output(), Some(start), e))?;
+
+    let mut checkpointer = match cfg.writes_output() {
+        true => Some(Checkpointer::new(&cfg, start)?),
+        false => None,
+    };
+
+    let mut discarder = match cfg.discard {
+        true => Some(
+            Discarder::new(o_file.as_file())
+                .map_err(|e| DdsError::io("reading metadata of", cfg.output(), None, e))?,
+        ),
+        false => None,
+    };
+
+    let mut undo_log = match &cfg.undo_log {
+        Some(path) => Some(UndoLog::create(path, o_file_size)?),
+        None => None,
+    };
+    if let (Some(checkpointer), Some(undo_log)) = (&mut checkpointer, &undo_log) {
+        checkpointer.sync_undo_log(undo_log)?;
+    }
+
+    let mut patch = match &cfg.export_patch {
+        // the size of a compressed input isn't known until it has all been read
+        Some(path) => Some((path, PatchWriter::create(path, 0)?, InputDigest::default())),
+        None => None,
+    };
+
+    let pb = ProgressBar::with_draw_target(Some(i_file_size), draw_target());
+    pb.set_position(start);
+    pb.set_style(progress_style());
+
+    Event::Start {
+        input: &cfg.input,
+        output: cfg.output(),
+        size: i_file_size,
+        threaded: false,
+        dry_run: cfg.dry_run,
+    }
+    .emit();
+    let mut progress = ProgressEvents::new(i_file_size);
+    let mut stats = Stats::default();
+
+    let mut report = DiffReport::default();
+    let mut i_reader = FillReader::new(i_file, start);
+    let mut o_reader = FillReader::new(o_file, start);
+    // a differing block of the input is moved into its job, and comes back once written
+    let pool = BufferPool::new(cfg.read_block);
+    let mut o_buffer = AlignedBuffer::new(cfg.read_block);
     loop {
-        let mut i_buffer = vec![0u8; BLOCK_SIZE];
+        let offset = i_reader.offset();
+        let mut i_buffer = pool.take();
 
         // read from the input and output into the buffer
-        let i_bytes_read = {
-            loop {
-                match i_file.read(&mut *i_buffer) {
-                    Ok(n) => break n,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {
-                        println!("Interrupted");
-                        continue;
-                    }
-                    Err(e) => panic!("Error reading from input file: {}", e),
-                }
-            }
-        };
-        let o_bytes_read = {
-            loop {
-                match o_file.read(&mut o_buffer) {
-                    Ok(n) => break n,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {
-                        println!("Interrupted");
-                        continue;
-                    }
-                    Err(e) => panic!("Error reading from output file: {}", e),
-                }
-            }
-        };
+        let reading = Instant::now();
+        let i_bytes_read = i_reader
+            .fill(&mut i_buffer)
+            .map_err(|e| DdsError::io("reading from", &cfg.input, Some(offset), e))?;
+        let o_bytes_read = o_reader
+            .fill(&mut o_buffer)
+            .map_err(|e| DdsError::io("reading from", cfg.output(), Some(offset), e))?;
+        stats.read_time += reading.elapsed();
 
         // if we read 0 bytes, we're done
-        if i_bytes_read == 0 || o_bytes_read == 0 {
+        if i_bytes_read == 0 && o_bytes_read == 0 {
             break;
         }
+        let bytes_read = i_bytes_read.min(o_bytes_read);
+
+        if let Some((_, _, digest)) = &mut patch {
+            digest.update(&i_buffer[..bytes_read]);
+        }
+        if let Some(undo_log) = &mut undo_log {
+            undo_log.compared(&o_buffer[..bytes_read]);
+        }
+
+        let job = WriteJob::diff(&cfg, i_buffer, &o_buffer, bytes_read, offset as usize);
+        let mut written = 0;
+        if let Some(job) = job {
+            let o_file = o_reader.get_mut();
+            let (job_offset, len, blocks) = (job.offset as u64, job.bytes(), job.len());
+            if cfg.dry_run {
+                report.record(&job);
+            } else {
+                if let Some(undo_log) = &mut undo_log {
+                    undo_log.record(&job, &o_buffer)?;
+                }
+                let writing = Instant::now();
+                if let Some((path, writer, _)) = &mut patch {
+                    writer
+                        .record(&job)
+                        .map_err(|e| DdsError::io("writing to", path, None, e))?;
+                    written = len;
+                } else if let Some(discarder) = &mut discarder {
+                    written = job.write_discarding(o_file, discarder)?;
+                } else {
+                    written = job.write(o_file)?;
+                }
+                stats.wrote(blocks, written, writing.elapsed());
+                Event::Write {
+                    offset: job_offset,
+                    len: written,
+                    blocks,
+                }
+                .emit();
+            }
+        }
+
+        let done = offset + bytes_read as u64;
+        stats.bytes_compared += bytes_read as u64;
+        pb.set_position(done);
+        progress.update(done);
 
-        if i_buffer != o_buffer {
-            let job = WriteJob::break_into_blocks(
-                i_buffer.clone(),
-                &o_buffer,
-                i_bytes_read,
-                read_blocks * BLOCK_SIZE,
-                MIN_BLOCK_SIZE,
-            );
-            debug_assert!(!job.is_empty());
-            job.write(&mut o_file).unwrap();
+        if let Some(checkpointer) = &mut checkpointer {
+            checkpointer.update(o_reader.get_ref().as_file(), written, done);
         }
 
-        read_blocks += 1;
-        pb.set_position((read_blocks * BLOCK_SIZE) as u64);
+        // one file ran out before the other
+        if i_bytes_read != o_bytes_read {
+            let input_read = i_reader.offset();
+            check_ends(&cfg, i_reader.get_mut(), input_read, o_file_size)?;
+            break;
+        }
     }
     pb.finish_with_message("Complete");
+
+    // the run is only over once the writes have reached the device
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.flush(
+            o_reader.get_ref().as_file(),
+            cfg.output(),
+            ProgressBar::with_draw_target(None, draw_target()),
+        )?;
+        checkpointer.finish();
+    }
+
+    if let Some(discarder) = discarder {
+        discarder.report();
+    }
+
+    if let Some(undo_log) = undo_log {
+        undo_log.finish()?;
+    }
+
+    if let Some((path, writer, digest)) = patch {
+        writer
+            .finish(digest)
+            .and_then(|file| file.sync_all())
+            .map_err(|e| DdsError::io("writing to", path, None, e))?;
+        notice!("Saved patch to {}", path);
+    }
+
+    if cfg.dry_run {
+        notice!("{}", report.to_string().trim_end());
+    }
+
+    if cfg.verify {
+        drop(o_reader);
+        check(&cfg)?;
+    }
+
+    let summary = stats.summary(started.elapsed());
+    notice!("{}", summary);
+    Event::Finish { summary }.emit();
+
+    Ok(())
 }
 
 pub fn controller(cfg: Dds) -> Result<(), DdsError> {
-    let stack_size = BLOCK_SIZE + 1024 * 1024;
-
     let thread = std::thread::Builder::new()
         .name("controller".to_string())
-        .stack_size(stack_size)
         .spawn(move || __controller(cfg))
-        .unwrap();
+        .map_err(|e| DdsError::thread("controller", e))?;
 
-    thread.join().unwrap();
-
-    Ok(())
+    thread
+        .join()
+        .map_err(|_| DdsError::thread("controller", "panicked"))?
 }
@@ -0,0 +1,142 @@
+use crate::{error::DdsError, input::Input, notice, Dds};
+
+/// Check the input and output are the same size before anything is written, unless told what to
+/// do with the difference.
+///
+/// A compressed input's size isn't known up front, so it is checked by `check_ends` once the
+/// compare loop reaches the end of either file.
+pub fn reconcile(cfg: &Dds, input: &mut Input, output: u64) -> Result<(), DdsError> {
+    let size = input.size();
+    if cfg.zero_tail {
+        input.pad_to(output);
+    }
+    check_sizes(cfg, size, output)
+}
+
+/// Check an input of `size` bytes, if known, fits an output of `output` bytes, saying what will
+/// happen to the difference.
+pub fn check_sizes(cfg: &Dds, size: Option<u64>, output: u64) -> Result<(), DdsError> {
+    let input = match size {
+        Some(size) => size,
+        None => return Ok(()),
+    };
+
+    if input > output {
+        if !cfg.allow_truncate {
+            return Err(DdsError::SizeMismatch { input, output });
+        }
+        notice!(
+            "The output is {} bytes smaller than the input, only the first {} bytes will be written",
+            input - output,
+            output
+        );
+    } else if input < output {
+        if cfg.zero_tail {
+            notice!("Zeroing the last {} bytes of the output", output - input);
+        } else if cfg.ignore_tail {
+            notice!(
+                "Leaving the last {} bytes of the output untouched",
+                output - input
+            );
+        } else {
+            return Err(DdsError::SizeMismatch { input, output });
+        }
+    }
+
+    Ok(())
+}
+
+/// Called when the compare loop reaches the end of one file before the other, after
+/// `input_read` bytes of the input have been read.
+pub fn check_ends(
+    cfg: &Dds,
+    input: &mut Input,
+    input_read: u64,
+    output: u64,
+) -> Result<(), DdsError> {
+    // the sizes were already checked up front
+    if input.size().is_some() {
+        return Ok(());
+    }
+
+    if input_read > output {
+        if cfg.allow_truncate {
+            notice!(
+                "The output is smaller than the input, only the first {} bytes were written",
+                output
+            );
+            return Ok(());
+        }
+
+        // read the rest of the input to say how much was left over
+        let rest = std::io::copy(input, &mut std::io::sink())
+            .map_err(|e| DdsError::io("reading from", &cfg.input, Some(input_read), e))?;
+        return Err(DdsError::SizeMismatch {
+            input: input_read + rest,
+            output,
+        });
+    }
+
+    if cfg.ignore_tail {
+        notice!(
+            "Left the last {} bytes of the output untouched",
+            output - input_read
+        );
+        return Ok(());
+    }
+    Err(DdsError::SizeMismatch {
+        input: input_read,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reconcile;
+    use crate::{error::DdsError, input::Input, Dds};
+
+    #[test]
+    fn test_reconcile_policies() {
+        let path = "test_reconcile_policies.img";
+        std::fs::write(path, vec![1u8; 4096]).unwrap();
+
+        let check = |cfg: Dds, output| {
+            let mut input = Input::open(path, false).unwrap();
+            reconcile(&cfg, &mut input, output)
+        };
+        let mismatch = |result| matches!(result, Err(DdsError::SizeMismatch { .. }));
+
+        let same = check(Dds::default(), 4096);
+        let smaller = check(Dds::default(), 2048);
+        let truncated = check(
+            Dds {
+                allow_truncate: true,
+                ..Default::default()
+            },
+            2048,
+        );
+        let larger = check(Dds::default(), 8192);
+        let ignored = check(
+            Dds {
+                ignore_tail: true,
+                ..Default::default()
+            },
+            8192,
+        );
+        let zeroed = check(
+            Dds {
+                zero_tail: true,
+                ..Default::default()
+            },
+            8192,
+        );
+        std::fs::remove_file(path).unwrap();
+
+        assert!(same.is_ok());
+        assert!(mismatch(smaller));
+        assert!(truncated.is_ok());
+        assert!(mismatch(larger));
+        assert!(ignored.is_ok());
+        assert!(zeroed.is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt::Display, time::Duration};
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+/// What a run did, gathered as it goes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// How much of the input was compared against the output
+    pub bytes_compared: u64,
+    /// How much was written to the output, or recorded into a patch
+    pub bytes_written: u64,
+    /// How many `WriteJob`s were written
+    pub jobs: u64,
+    /// How many blocks the writes were split into
+    pub blocks: u64,
+    /// Time spent reading the input and output, only counted once where the two are read at once
+    pub read_time: Duration,
+    /// Time spent writing to the output
+    pub write_time: Duration,
+    /// Time the threaded writer spent waiting for each of its jobs to arrive
+    pub write_wait: Duration,
+}
+
+impl Stats {
+    /// Count a `WriteJob` of `blocks` blocks that wrote `written` bytes in `took`.
+    pub fn wrote(&mut self, blocks: usize, written: usize, took: Duration) {
+        self.jobs += 1;
+        self.blocks += blocks as u64;
+        self.bytes_written += written as u64;
+        self.write_time += took;
+    }
+
+    /// Combine the stats kept by different threads of the same run.
+    pub fn merge(self, other: Stats) -> Stats {
+        Stats {
+            bytes_compared: self.bytes_compared + other.bytes_compared,
+            bytes_written: self.bytes_written + other.bytes_written,
+            jobs: self.jobs + other.jobs,
+            blocks: self.blocks + other.blocks,
+            read_time: self.read_time + other.read_time,
+            write_time: self.write_time + other.write_time,
+            write_wait: self.write_wait + other.write_wait,
+        }
+    }
+
+    /// Combine the stats of two readers that ran side by side, so only the longer of their times
+    /// spent reading counts.
+    pub fn merge_readers(self, other: Stats) -> Stats {
+        Stats {
+            read_time: self.read_time.max(other.read_time),
+            ..self.merge(other)
+        }
+    }
+
+    pub fn summary(&self, duration: Duration) -> Summary {
+        let rate = |bytes: u64, time: Duration| match time.is_zero() {
+            true => 0.0,
+            false => bytes as f64 / time.as_secs_f64(),
+        };
+        Summary {
+            bytes_compared: self.bytes_compared,
+            bytes_written: self.bytes_written,
+            percent_changed: match self.bytes_compared {
+                0 => 0.0,
+                compared => self.bytes_written as f64 * 100.0 / compared as f64,
+            },
+            jobs: self.jobs,
+            blocks: self.blocks,
+            duration_secs: duration.as_secs_f64(),
+            throughput_bytes_per_sec: rate(self.bytes_compared, duration),
+            read_secs: self.read_time.as_secs_f64(),
+            read_bytes_per_sec: rate(self.bytes_compared, self.read_time),
+            write_secs: self.write_time.as_secs_f64(),
+            write_bytes_per_sec: rate(self.bytes_written, self.write_time),
+            average_write_wait_secs: match self.jobs {
+                0 => 0.0,
+                jobs => self.write_wait.as_secs_f64() / jobs as f64,
+            },
+        }
+    }
+}
+
+/// The stats of a finished run, as reported at the end of it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub bytes_compared: u64,
+    pub bytes_written: u64,
+    pub percent_changed: f64,
+    pub jobs: u64,
+    pub blocks: u64,
+    pub duration_secs: f64,
+    /// How fast the input was compared against the output, over the whole run
+    pub throughput_bytes_per_sec: f64,
+    pub read_secs: f64,
+    pub read_bytes_per_sec: f64,
+    pub write_secs: f64,
+    pub write_bytes_per_sec: f64,
+    /// How long the threaded writer sat idle before each job, a long wait means the reader is
+    /// what holds the run up
+    pub average_write_wait_secs: f64,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rate = |bytes_per_sec: f64| HumanBytes(bytes_per_sec as u64);
+        writeln!(
+            f,
+            "Compared {} in {:.2}s ({}/s), {:.2}% changed",
+            HumanBytes(self.bytes_compared),
+            self.duration_secs,
+            rate(self.throughput_bytes_per_sec),
+            self.percent_changed
+        )?;
+        writeln!(
+            f,
+            "Wrote {} in {} jobs of {} blocks",
+            HumanBytes(self.bytes_written),
+            self.jobs,
+            self.blocks
+        )?;
+        write!(
+            f,
+            "Spent {:.2}s reading ({}/s) and {:.2}s writing ({}/s)",
+            self.read_secs,
+            rate(self.read_bytes_per_sec),
+            self.write_secs,
+            rate(self.write_bytes_per_sec)
+        )?;
+        if self.average_write_wait_secs > 0.0 {
+            write!(
+                f,
+                "\nThe writer waited {:.3}ms for each job on average",
+                self.average_write_wait_secs * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Stats;
+
+    #[test]
+    fn test_summary() {
+        let mut reader = Stats {
+            bytes_compared: 4096,
+            read_time: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut writer = Stats::default();
+        writer.wrote(2, 1024, Duration::from_millis(250));
+        writer.wrote(1, 1024, Duration::from_millis(250));
+        writer.write_wait = Duration::from_millis(10);
+        reader.bytes_compared += 4096;
+
+        let summary = reader.merge(writer).summary(Duration::from_secs(2));
+        assert_eq!(summary.bytes_compared, 8192);
+        assert_eq!(summary.bytes_written, 2048);
+        assert_eq!(summary.percent_changed, 25.0);
+        assert_eq!((summary.jobs, summary.blocks), (2, 3));
+        assert_eq!(summary.throughput_bytes_per_sec, 4096.0);
+        assert_eq!(summary.read_bytes_per_sec, 8192.0);
+        assert_eq!(summary.write_bytes_per_sec, 4096.0);
+        assert_eq!(summary.average_write_wait_secs, 0.005);
+
+        assert_eq!(
+            summary.to_string(),
+            "Compared 8.00 KiB in 2.00s (4.00 KiB/s), 25.00% changed\n\
+             Wrote 2.00 KiB in 2 jobs of 3 blocks\n\
+             Spent 1.00s reading (8.00 KiB/s) and 0.50s writing (4.00 KiB/s)\n\
+             The writer waited 5.000ms for each job on average"
+        );
+    }
+
+    #[test]
+    fn test_merge_readers() {
+        let input = Stats {
+            bytes_compared: 4096,
+            read_time: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let output = Stats {
+            read_time: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let merged = input.merge_readers(output);
+        assert_eq!(merged.read_time, Duration::from_secs(3));
+        assert_eq!(merged.bytes_compared, 4096);
+        assert_eq!(input.merge(output).read_time, Duration::from_secs(5));
+    }
+}
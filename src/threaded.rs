@@ -1,151 +1,638 @@
 use std::{
-    fmt::Write,
-    fs::OpenOptions,
-    io::Read,
-    sync::mpsc::{Receiver, SyncSender},
+    collections::BTreeMap,
+    fs::File,
+    io::{Seek, SeekFrom},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::ScopedJoinHandle,
     time::Instant,
 };
 
-use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar};
 
 use crate::{
+    backup::{create_image, resize_image},
+    checkpoint::{start_offset, Checkpointer},
+    device,
+    direct::DirectFile,
+    discard::Discarder,
     error::DdsError,
-    utils::{validate_paths, WriteJob},
-    Dds, BLOCK_SIZE, MIN_BLOCK_SIZE,
+    events::{draw_target, set_format, Event, ProgressEvents},
+    input::{progress_length, Input},
+    lock::open_output,
+    notice,
+    patch::{InputDigest, PatchWriter},
+    pool::{BufferPool, PooledBuffer},
+    reader::FillReader,
+    report::DiffReport,
+    size::{check_ends, reconcile},
+    stats::Stats,
+    undo::UndoLog,
+    utils::{progress_style, validate_paths, WriteJob},
+    verify::check,
+    Dds,
 };
 
-fn reader(cfg: &Dds, write_q: SyncSender<WriteJob>, pb: ProgressBar) {
-    // open the input and output files
-    let mut i_file = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .open(&cfg.input)
-        .unwrap();
-
-    let mut o_file = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .open(&cfg.output)
-        .unwrap();
+/// How many blocks may wait between the readers and the comparers.
+const QUEUE_DEPTH: usize = 16;
+/// How many jobs may wait for the writer.
+const WRITE_QUEUE_DEPTH: usize = 100;
+/// The most threads that compare blocks at once.
+const MAX_COMPARERS: usize = 4;
 
-    // get the size of the file
-    let i_file_size = i_file.metadata().unwrap().len();
+/// A block read from the input.
+struct InputBlock {
+    offset: u64,
+    data: PooledBuffer,
+    len: usize,
+}
+
+/// The same block read from both the input and the output.
+struct Pair {
+    offset: u64,
+    input: PooledBuffer,
+    output: PooledBuffer,
+    /// How much of the block both files hold
+    len: usize,
+}
 
-    pb.set_length(i_file_size);
-    pb.set_position(0);
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#>-"));
+/// A block the comparers are done with, and the job that brings the output in line, if any.
+struct Compared {
+    offset: u64,
+    len: u64,
+    job: Option<WriteJob>,
+}
+
+/// Tracks how far into the output every block has been dealt with, as the comparers finish them
+/// out of order.
+struct Frontier {
+    done: u64,
+    finished: BTreeMap<u64, u64>,
+}
 
-    let mut read_blocks = 0;
-    let mut o_buffer = [0u8; BLOCK_SIZE];
+impl Frontier {
+    fn new(start: u64) -> Frontier {
+        Frontier {
+            done: start,
+            finished: BTreeMap::new(),
+        }
+    }
+
+    /// Mark `len` bytes at `offset` as done, returning the offset everything before which is.
+    fn complete(&mut self, offset: u64, len: u64) -> u64 {
+        self.finished.insert(offset, len);
+        while let Some(len) = self.finished.remove(&self.done) {
+            self.done += len;
+        }
+        self.done
+    }
+}
+
+/// Read the input a block at a time, handing each block to the device reader.
+///
+/// When exporting a patch, the checksum of the input is returned once it has all been read.
+fn input_reader(
+    cfg: &Dds,
+    mut reader: FillReader<Input>,
+    o_file_size: u64,
+    pool: Arc<BufferPool>,
+    input_q: SyncSender<InputBlock>,
+    mut digest: Option<InputDigest>,
+) -> Result<(Option<InputDigest>, Stats), DdsError> {
+    let mut stats = Stats::default();
     loop {
-        // allocate buffers on the heap
-        let mut i_buffer = vec![0u8; BLOCK_SIZE];
-
-        // read from the input and output into the buffer
-        let i_bytes_read = {
-            loop {
-                match i_file.read(&mut *i_buffer) {
-                    Ok(n) => break n,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => panic!("Error reading from input file: {}", e),
-                }
-            }
+        let offset = reader.offset();
+        let mut data = pool.take();
+
+        let reading = Instant::now();
+        let len = reader
+            .fill(&mut data)
+            .map_err(|e| DdsError::io("reading from", &cfg.input, Some(offset), e))?;
+        stats.read_time += reading.elapsed();
+
+        // nothing left of the input, or nothing left of the output to compare it against
+        if len == 0 || offset >= o_file_size {
+            break;
+        }
+
+        if let Some(digest) = &mut digest {
+            let compared = len.min((o_file_size - offset) as usize);
+            digest.update(&data[..compared]);
+        }
+        if input_q.send(InputBlock { offset, data, len }).is_err() {
+            // a later stage has stopped, and will report why
+            return Ok((None, stats));
+        }
+
+        if len < cfg.read_block || reader.offset() > o_file_size {
+            break;
+        }
+    }
+
+    // one file ran out before the other
+    let input_read = reader.offset();
+    if input_read != o_file_size {
+        check_ends(cfg, reader.get_mut(), input_read, o_file_size)?;
+    }
+
+    Ok((digest, stats))
+}
+
+/// Read the output alongside each block of the input, handing both to the comparers.
+fn device_reader(
+    cfg: &Dds,
+    mut reader: FillReader<DirectFile>,
+    pool: Arc<BufferPool>,
+    input_q: Receiver<InputBlock>,
+    pair_q: SyncSender<Pair>,
+    undo_log: Option<&Mutex<UndoLog>>,
+) -> Result<Stats, DdsError> {
+    let mut stats = Stats::default();
+    while let Ok(block) = input_q.recv() {
+        let mut output = pool.take();
+
+        let reading = Instant::now();
+        let o_len = reader
+            .fill(&mut output[..block.len])
+            .map_err(|e| DdsError::io("reading from", cfg.output(), Some(block.offset), e))?;
+        stats.read_time += reading.elapsed();
+
+        let len = block.len.min(o_len);
+        // blocks reach the undo log in order here, the comparers may finish them out of order
+        if let Some(undo_log) = undo_log {
+            lock(undo_log).compared(&output[..len]);
+        }
+
+        let pair = Pair {
+            offset: block.offset,
+            input: block.data,
+            output,
+            len,
         };
-        let o_bytes_read = {
-            loop {
-                match o_file.read(&mut o_buffer) {
-                    Ok(n) => break n,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => panic!("Error reading from output file: {}", e),
-                }
-            }
+        if pair_q.send(pair).is_err() {
+            break;
+        }
+    }
+    Ok(stats)
+}
+
+/// Compare pairs of blocks, sending what needs writing on to the writer.
+fn comparer(
+    cfg: &Dds,
+    pair_q: Arc<Mutex<Receiver<Pair>>>,
+    write_q: SyncSender<Compared>,
+    undo_log: Option<&Mutex<UndoLog>>,
+    pb: &ProgressBar,
+    progress: &Mutex<ProgressEvents>,
+) -> Result<Stats, DdsError> {
+    let mut stats = Stats::default();
+    loop {
+        // only hold the queue while waiting for a pair, not while comparing it
+        let pair = match lock(&pair_q).recv() {
+            Ok(pair) => pair,
+            Err(_) => break,
         };
 
-        // if we read 0 bytes, we're done
-        if i_bytes_read == 0 || o_bytes_read == 0 {
+        let job = WriteJob::diff(
+            cfg,
+            pair.input,
+            &pair.output,
+            pair.len,
+            pair.offset as usize,
+        );
+        // the writer can't get to a block before its old contents are saved
+        if let (Some(job), Some(undo_log)) = (&job, undo_log) {
+            lock(undo_log).record(job, &pair.output)?;
+        }
+
+        stats.bytes_compared += pair.len as u64;
+        pb.inc(pair.len as u64);
+        lock(progress).update(pb.position());
+
+        let compared = Compared {
+            offset: pair.offset,
+            len: pair.len as u64,
+            job,
+        };
+        if write_q.send(compared).is_err() {
             break;
         }
+    }
+    Ok(stats)
+}
+
+/// Write every job to the output as the comparers finish them, saving checkpoints as the blocks
+/// before them are all done.
+fn writer(
+    cfg: &Dds,
+    mut o_file: DirectFile,
+    mut checkpointer: Checkpointer,
+    start: u64,
+    write_q: Receiver<Compared>,
+    pb: MultiProgress,
+) -> Result<(Checkpointer, Stats), DdsError> {
+    let mut discarder = match cfg.discard {
+        true => Some(
+            Discarder::new(o_file.as_file())
+                .map_err(|e| DdsError::io("reading metadata of", cfg.output(), None, e))?,
+        ),
+        false => None,
+    };
 
-        if i_buffer != o_buffer {
-            let job = WriteJob::break_into_blocks(
-                i_buffer,
-                &o_buffer,
-                i_bytes_read,
-                read_blocks * BLOCK_SIZE,
-                MIN_BLOCK_SIZE,
-            );
-            debug_assert!(!job.is_empty());
-            write_q.send(job).unwrap();
+    let mut stats = Stats::default();
+    let mut frontier = Frontier::new(start);
+
+    // loop until the write queue is empty, timing how long the writer sat waiting
+    loop {
+        let waiting = Instant::now();
+        let Ok(Compared { offset, len, job }) = write_q.recv() else {
+            break;
+        };
+        let waited = waiting.elapsed();
+
+        let mut written = 0;
+        if let Some(job) = job {
+            // only jobs count towards the wait, the average is taken over them
+            stats.write_wait += waited;
+            // failing to log progress shouldn't stop the restore
+            let _ = pb.println(format!(
+                "Wrote {} bytes at offset [{}]",
+                job.bytes(),
+                &job.offset
+            ));
+
+            let (offset, blocks) = (job.offset as u64, job.len());
+            let writing = Instant::now();
+            written = match &mut discarder {
+                Some(discarder) => job.write_discarding(&mut o_file, discarder)?,
+                None => job.write(&mut o_file)?,
+            };
+            stats.wrote(blocks, written, writing.elapsed());
+            Event::Write {
+                offset,
+                len: written,
+                blocks,
+            }
+            .emit();
         }
 
-        pb.set_position(((read_blocks + 1) * BLOCK_SIZE) as u64);
-        read_blocks += 1;
+        // a checkpoint can only be taken past blocks that are all written
+        let done = frontier.complete(offset, len);
+        checkpointer.update(o_file.as_file(), written, done);
     }
-    pb.finish_with_message("Complete");
+
+    // the run is only over once the writes have reached the device
+    checkpointer.flush(
+        o_file.as_file(),
+        cfg.output(),
+        pb.add(ProgressBar::new_spinner()),
+    )?;
+
+    if let Some(discarder) = discarder {
+        discarder.report();
+    }
+
+    Ok((checkpointer, stats))
 }
 
-fn writer(cfg: &Dds, write_q: Receiver<WriteJob>, pb: MultiProgress) {
-    // open the output file
-    let mut o_file = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(false)
-        .open(&cfg.output)
-        .unwrap();
+/// Stands in for the writer during a dry run, collecting every job into a report instead.
+///
+/// Jobs arrive out of order, so their regions are sorted before the report merges them.
+fn collector(write_q: Receiver<Compared>) -> DiffReport {
+    let mut regions = Vec::new();
+    while let Ok(compared) = write_q.recv() {
+        if let Some(job) = compared.job {
+            regions.extend(job.regions());
+        }
+    }
+    regions.sort_unstable_by_key(|&(offset, _)| offset);
 
-    let mut average = 0;
-    let mut samples = 0;
+    let mut report = DiffReport::default();
+    for (offset, len) in regions {
+        report.add(offset..offset + len as u64);
+    }
+    report
+}
 
-    // loop until the write queue is empty
-    let mut start = std::time::Instant::now();
-    while let Ok(job) = write_q.recv() {
-        samples += 1;
+/// Stands in for the writer while exporting, recording every job into the patch instead.
+///
+/// Jobs arrive out of order, which a patch doesn't mind as every record carries its offset.
+fn patcher(
+    path: &str,
+    mut patch: PatchWriter<File>,
+    write_q: Receiver<Compared>,
+) -> Result<(PatchWriter<File>, Stats), DdsError> {
+    let mut stats = Stats::default();
+    while let Ok(compared) = write_q.recv() {
+        if let Some(job) = compared.job {
+            let writing = Instant::now();
+            patch
+                .record(&job)
+                .map_err(|e| DdsError::io("writing to", path, None, e))?;
+            stats.wrote(job.len(), job.bytes(), writing.elapsed());
+            Event::Write {
+                offset: job.offset as u64,
+                len: job.bytes(),
+                blocks: job.len(),
+            }
+            .emit();
+        }
+    }
+    Ok((patch, stats))
+}
 
-        average -= average / samples;
-        average += (Instant::now() - start).as_nanos() as u64 / samples;
+/// Lock state shared between the stages.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .expect("no stage panics while holding shared state")
+}
 
-        pb.println(format!(
-            "Wrote {} bytes at offset [{}]",
-            job.data.len(),
-            &job.offset
-        ))
-        .unwrap();
+/// Wait for a worker thread, turning a panic into an error.
+pub(crate) fn join<T>(
+    handle: ScopedJoinHandle<'_, Result<T, DdsError>>,
+    name: &str,
+) -> Result<T, DdsError> {
+    handle
+        .join()
+        .map_err(|_| DdsError::thread(name, "panicked"))?
+}
 
-        job.write(&mut o_file).unwrap();
+/// Wait for every stage that feeds the writer, merging their stats.
+fn join_upstream<'scope>(
+    input: ScopedJoinHandle<'scope, Result<(Option<InputDigest>, Stats), DdsError>>,
+    device: ScopedJoinHandle<'scope, Result<Stats, DdsError>>,
+    comparers: Vec<ScopedJoinHandle<'scope, Result<Stats, DdsError>>>,
+    pb: &ProgressBar,
+) -> Result<(Option<InputDigest>, Stats), DdsError> {
+    // join every thread before reporting the first failure, in pipeline order
+    let comparers: Vec<_> = comparers
+        .into_iter()
+        .map(|handle| join(handle, "comparer"))
+        .collect();
+    let device = join(device, "device reader");
+    let input = join(input, "input reader");
 
-        // start timer
-        start = Instant::now();
+    let result = (|| {
+        let (digest, mut stats) = input?;
+        // the readers wait on their files at the same time
+        stats = stats.merge_readers(device?);
+        for comparer in comparers {
+            stats = stats.merge(comparer?);
+        }
+        Ok((digest, stats))
+    })();
+    match result {
+        Ok(_) => pb.finish_with_message("Complete"),
+        Err(_) => pb.abandon(),
     }
+    result
 }
 
+/// Restore the output with a pipeline of threads: one reading the input, one reading the output,
+/// a pool comparing the blocks, and one writing the differences.
 pub fn controller(cfg: Dds) -> Result<(), DdsError> {
-    validate_paths(&cfg);
+    set_format(cfg.output_format);
+    let started = Instant::now();
+    validate_paths(&cfg)?;
+    cfg.validate()?;
+
+    if cfg.backup && !cfg.dry_run {
+        create_image(&cfg)?;
+    }
+
+    let start = start_offset(&cfg, cfg.read_block as u64)?;
+
+    // the output is opened, and locked, once, the device reader and writer share it
+    let o_file = open_output(cfg.output(), cfg.writes_output(), cfg.direct)?;
+    // the image is only resized once no other run can be writing to it
+    if cfg.backup && !cfg.dry_run {
+        resize_image(&cfg, o_file.as_file())?;
+    }
+    let mut o_reader = o_file
+        .try_clone()
+        .map_err(|e| DdsError::io("opening", cfg.output(), None, e))?;
+    let mut i_file = Input::open(&cfg.input, cfg.direct)?;
+
+    // get the size of the file
+    // make sure the input and output line up, before anything is written
+    let o_file_size = device::size(o_file.as_file())
+        .map_err(|e| DdsError::io("finding the size of", cfg.output(), None, e))?;
+    reconcile(&cfg, &mut i_file, o_file_size)?;
+    let i_file_size = progress_length(&i_file, o_file.as_file(), cfg.output())?;
+
+    // pick up where a previous run left off
+    i_file
+        .skip_to(start)
+        .map_err(|e| DdsError::io("seeking in", &cfg.input, Some(start), e))?;
+    o_reader
+        .seek(SeekFrom::Start(start))
+        .map_err(|e| DdsError::io("seeking in", cfg.output(), Some(start), e))?;
+
+    let m_pb = MultiProgress::with_draw_target(draw_target());
+    let pb = m_pb.add(ProgressBar::new(i_file_size));
+    pb.set_position(start);
+    pb.set_style(progress_style());
+
+    Event::Start {
+        input: &cfg.input,
+        output: cfg.output(),
+        size: i_file_size,
+        threaded: true,
+        dry_run: cfg.dry_run,
+    }
+    .emit();
+    let progress = Mutex::new(ProgressEvents::new(i_file_size));
+
+    let digest = cfg.export_patch.as_ref().map(|_| InputDigest::default());
+    let undo_log = match &cfg.undo_log {
+        Some(path) => Some(Mutex::new(UndoLog::create(path, o_file_size)?)),
+        None => None,
+    };
+    let pool = BufferPool::new(cfg.read_block);
+    let comparers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .clamp(1, MAX_COMPARERS);
+
+    let stats = std::thread::scope(|scope| {
+        let (cfg, pb, progress, undo_log) = (&cfg, &pb, &progress, undo_log.as_ref());
+        let (input_q_tx, input_q_rx) = sync_channel(QUEUE_DEPTH);
+        let (pair_q_tx, pair_q_rx) = sync_channel(QUEUE_DEPTH);
+        let (write_q_tx, write_q_rx) = sync_channel(WRITE_QUEUE_DEPTH);
+        let pair_q_rx = Arc::new(Mutex::new(pair_q_rx));
+
+        let i_reader = FillReader::new(i_file, start);
+        let input_pool = Arc::clone(&pool);
+        let input_thread = std::thread::Builder::new()
+            .name("input_reader".to_string())
+            .spawn_scoped(scope, move || {
+                input_reader(cfg, i_reader, o_file_size, input_pool, input_q_tx, digest)
+            })
+            .map_err(|e| DdsError::thread("input reader", e))?;
 
-    let m_pb = MultiProgress::new();
+        let o_reader = FillReader::new(o_reader, start);
+        let device_pool = Arc::clone(&pool);
+        let device_thread = std::thread::Builder::new()
+            .name("device_reader".to_string())
+            .spawn_scoped(scope, move || {
+                device_reader(cfg, o_reader, device_pool, input_q_rx, pair_q_tx, undo_log)
+            })
+            .map_err(|e| DdsError::thread("device reader", e))?;
 
-    // create a scoped thread
-    std::thread::scope(|scope| {
-        let (write_q_tx, write_q_rx) = std::sync::mpsc::sync_channel(100);
+        let mut comparer_threads = Vec::with_capacity(comparers);
+        for i in 0..comparers {
+            let (pair_q_rx, write_q_tx) = (Arc::clone(&pair_q_rx), write_q_tx.clone());
+            let handle = std::thread::Builder::new()
+                .name(format!("comparer_{}", i))
+                .spawn_scoped(scope, move || {
+                    comparer(cfg, pair_q_rx, write_q_tx, undo_log, pb, progress)
+                })
+                .map_err(|e| DdsError::thread("comparer", e))?;
+            comparer_threads.push(handle);
+        }
+        // the writer stops once every comparer has let go of the queue
+        drop((pair_q_rx, write_q_tx));
+
+        if cfg.dry_run {
+            let collector_thread = scope.spawn(|| Ok(collector(write_q_rx)));
+
+            let upstream = join_upstream(input_thread, device_thread, comparer_threads, pb);
+            let report = join(collector_thread, "collector")?;
+            let (_, stats) = upstream?;
+            notice!("{}", report.to_string().trim_end());
+            Ok(stats)
+        } else if let Some(path) = &cfg.export_patch {
+            // the size of a compressed input isn't known until it has all been read
+            let patch = PatchWriter::create(path, 0)?;
+            let patcher_thread = scope.spawn(|| patcher(path, patch, write_q_rx));
+
+            let upstream = join_upstream(input_thread, device_thread, comparer_threads, pb);
+            let (patch, patch_stats) = join(patcher_thread, "patcher")?;
+            let (digest, stats) = upstream?;
+            let digest = digest.expect("the input reader hashes the input while exporting");
 
-        let stack_size = BLOCK_SIZE + 1024 * 1024;
-        let pb = m_pb.add(ProgressBar::new(0));
-        let reader_thread = std::thread::Builder::new()
-            .stack_size(stack_size)
-            .name("reader_thread".to_string())
-            .spawn_scoped(scope, || reader(&cfg, write_q_tx, pb))
-            .unwrap();
+            patch
+                .finish(digest)
+                .and_then(|file| file.sync_all())
+                .map_err(|e| DdsError::io("writing to", path, None, e))?;
+            notice!("Saved patch to {}", path);
+            Ok(stats.merge(patch_stats))
+        } else {
+            let mut checkpointer = Checkpointer::new(cfg, start)?;
+            if let Some(undo_log) = undo_log {
+                checkpointer.sync_undo_log(&lock(undo_log))?;
+            }
+            let writer_thread =
+                scope.spawn(|| writer(cfg, o_file, checkpointer, start, write_q_rx, m_pb));
+
+            // a failed writer is why the other stages stopped early
+            let upstream = join_upstream(input_thread, device_thread, comparer_threads, pb);
+            let (checkpointer, write_stats) = join(writer_thread, "writer")?;
+            let (_, stats) = upstream?;
 
-        let writer_thread = scope.spawn(|| writer(&cfg, write_q_rx, m_pb));
+            checkpointer.finish();
+            Ok(stats.merge(write_stats))
+        }
+    })?;
 
-        // wait for the threads to finish
-        reader_thread.join().unwrap();
-        writer_thread.join().unwrap();
-    });
+    if let Some(undo_log) = undo_log {
+        undo_log
+            .into_inner()
+            .expect("no stage panics while holding shared state")
+            .finish()?;
+    }
+
+    if cfg.verify {
+        check(&cfg)?;
+    }
+
+    let summary = stats.summary(started.elapsed());
+    notice!("{}", summary);
+    Event::Finish { summary }.emit();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc::sync_channel, Arc, Mutex};
+
+    use indicatif::ProgressBar;
+
+    use super::{collector, comparer, Frontier, Pair, MAX_COMPARERS};
+    use crate::{
+        events::ProgressEvents, pool::BufferPool, report::DiffReport, utils::WriteJob, Dds,
+    };
+
+    #[test]
+    fn test_collector_matches_single_report() {
+        let cfg = Dds {
+            read_block: 4096,
+            write_granularity: 512,
+            ..Default::default()
+        };
+        // differences at both ends of every block, so ranges run across block boundaries, and a
+        // clean block every so often to split them
+        let blocks: Vec<(u64, Vec<u8>, Vec<u8>)> = (0..64u64)
+            .map(|i| {
+                let output = vec![0u8; 4096];
+                let mut input = output.clone();
+                if i % 5 != 0 {
+                    input[0] = 1;
+                    input[4095] = 1;
+                }
+                (i * 4096, input, output)
+            })
+            .collect();
+
+        let mut single = DiffReport::default();
+        for (offset, input, output) in &blocks {
+            if let Some(job) = WriteJob::diff(&cfg, input.clone(), output, 4096, *offset as usize) {
+                single.record(&job);
+            }
+        }
+
+        let pool = BufferPool::new(4096);
+        let (pair_q_tx, pair_q_rx) = sync_channel(blocks.len());
+        // handed to the comparers backwards, so the jobs can't reach the collector in order
+        for (offset, input, output) in blocks.iter().rev() {
+            let (mut i_buffer, mut o_buffer) = (pool.take(), pool.take());
+            i_buffer.copy_from_slice(input);
+            o_buffer.copy_from_slice(output);
+            let pair = Pair {
+                offset: *offset,
+                input: i_buffer,
+                output: o_buffer,
+                len: 4096,
+            };
+            pair_q_tx.send(pair).unwrap();
+        }
+        drop(pair_q_tx);
+
+        let (pb, progress) = (ProgressBar::hidden(), Mutex::new(ProgressEvents::new(0)));
+        let pair_q_rx = Arc::new(Mutex::new(pair_q_rx));
+        let (write_q_tx, write_q_rx) = sync_channel(blocks.len());
+        let threaded = std::thread::scope(|scope| {
+            for _ in 0..MAX_COMPARERS {
+                let (pair_q_rx, write_q_tx) = (Arc::clone(&pair_q_rx), write_q_tx.clone());
+                let (cfg, pb, progress) = (&cfg, &pb, &progress);
+                scope.spawn(move || comparer(cfg, pair_q_rx, write_q_tx, None, pb, progress));
+            }
+            drop(write_q_tx);
+            collector(write_q_rx)
+        });
+
+        assert_eq!(threaded, single);
+    }
+
+    #[test]
+    fn test_frontier_waits_for_earlier_blocks() {
+        let mut frontier = Frontier::new(1024);
+        assert_eq!(frontier.complete(2048, 1024), 1024);
+        assert_eq!(frontier.complete(4096, 512), 1024);
+        assert_eq!(frontier.complete(1024, 1024), 3072);
+        assert_eq!(frontier.complete(3072, 1024), 4608);
+    }
+}
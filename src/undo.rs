@@ -0,0 +1,207 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+};
+
+use indicatif::ProgressBar;
+
+use crate::{
+    device,
+    error::DdsError,
+    events::draw_target,
+    lock::open_output,
+    notice,
+    patch::{hash_output, InputDigest, PatchReader, PatchWriter, RECORD_HEADER_SIZE},
+    utils::{progress_style, WriteJob},
+    UndoArgs,
+};
+
+/// Records the original contents of everything a run overwrites, so `dds undo` can put it back.
+///
+/// An undo log is a patch whose input is the output as it was before the run: its records hold
+/// the old data of every block, and its checksum covers every byte of the output compared.
+pub struct UndoLog {
+    path: String,
+    log: PatchWriter<File>,
+    digest: InputDigest,
+}
+
+impl UndoLog {
+    /// Start an undo log for an output of `size` bytes.
+    pub fn create(path: &str, size: u64) -> Result<UndoLog, DdsError> {
+        Ok(UndoLog {
+            path: path.to_string(),
+            log: PatchWriter::create(path, size)?,
+            digest: InputDigest::default(),
+        })
+    }
+
+    /// Take in a block of the output as it was read, before anything is written over it.
+    pub fn compared(&mut self, output: &[u8]) {
+        self.digest.update(output);
+    }
+
+    /// Save what `job` is about to overwrite in `output`, the block of the output it was compared
+    /// against.
+    ///
+    /// The records are handed to the OS before returning, so everything written after this can be
+    /// undone even if `dds` is killed part way through.
+    pub fn record(&mut self, job: &WriteJob, output: &[u8]) -> Result<(), DdsError> {
+        self.log
+            .record_chunks(job.originals(output))
+            .and_then(|_| self.log.flush())
+            .map_err(|e| DdsError::io("writing to", &self.path, None, e))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Another handle to the log file, for syncing it whenever the output is synced.
+    pub fn try_clone_file(&self) -> Result<File, DdsError> {
+        self.log
+            .get_ref()
+            .try_clone()
+            .map_err(|e| DdsError::io("opening", &self.path, None, e))
+    }
+
+    /// Close off the log once the run is over.
+    pub fn finish(self) -> Result<(), DdsError> {
+        self.log
+            .finish(self.digest)
+            .and_then(|file| file.sync_all())
+            .map_err(|e| DdsError::io("writing to", &self.path, None, e))?;
+        notice!("Saved undo log to {}", self.path);
+        Ok(())
+    }
+}
+
+/// Put back everything recorded in an undo log, last write first.
+pub fn undo(args: &UndoArgs) -> Result<(), DdsError> {
+    let log_err = |e: std::io::Error| DdsError::Undo {
+        path: args.log.clone(),
+        reason: e.to_string(),
+    };
+
+    let log = File::open(&args.log).map_err(|e| DdsError::io("opening", &args.log, None, e))?;
+    let mut log = PatchReader::new(BufReader::new(log)).map_err(log_err)?;
+
+    // find every record first, so they can be replayed in reverse
+    let mut records = Vec::new();
+    loop {
+        let position = log.position();
+        match log.skip_record() {
+            Ok(Some((offset, len))) => records.push((position, offset, len)),
+            Ok(None) => break,
+            // the run was interrupted, but each write only happened once its record was complete
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(log_err(e)),
+        }
+    }
+    let finished = log.is_finished();
+    if !finished {
+        notice!(
+            "{} was never finished, the run that saved it was interrupted. Undoing the {} writes it recorded",
+            args.log,
+            records.len()
+        );
+    }
+    let header = log.header.clone();
+    let mut log = log.into_inner();
+
+    let mut o_file = open_output(&args.output, true, false)?;
+
+    // a block device can't grow to fit the log
+    let o_file_size = device::size(o_file.as_file())
+        .map_err(|e| DdsError::io("finding the size of", &args.output, None, e))?;
+    let is_block_device = device::is_block_device(o_file.as_file())
+        .map_err(|e| DdsError::io("reading metadata of", &args.output, None, e))?;
+    if is_block_device && o_file_size < header.input_size {
+        return Err(DdsError::SizeMismatch {
+            input: header.input_size,
+            output: o_file_size,
+        });
+    }
+
+    let total = records.iter().map(|&(_, _, len)| len).sum();
+    let pb = ProgressBar::with_draw_target(Some(total), draw_target());
+    pb.set_style(progress_style());
+
+    let mut data = Vec::new();
+    for &(position, offset, len) in records.iter().rev() {
+        data.resize(len as usize, 0);
+        log.seek(SeekFrom::Start(position + RECORD_HEADER_SIZE))
+            .and_then(|_| log.read_exact(&mut data))
+            .map_err(log_err)?;
+
+        let write_err = |source| DdsError::Write {
+            offset,
+            len: data.len(),
+            source,
+        };
+        o_file.seek(SeekFrom::Start(offset)).map_err(write_err)?;
+        o_file.write_all(&data).map_err(write_err)?;
+        pb.inc(len);
+    }
+    o_file
+        .as_file()
+        .sync_all()
+        .map_err(|e| DdsError::io("syncing", &args.output, None, e))?;
+    pb.finish_with_message("Complete");
+
+    if args.verify {
+        if !finished {
+            notice!("Not verifying, an unfinished undo log has no checksum to check against");
+            return Ok(());
+        }
+        notice!("Verifying {}", &args.output);
+        if hash_output(&mut o_file, &args.output, header.input_size)? != header.input_sha256 {
+            return Err(DdsError::Undo {
+                path: args.log.clone(),
+                reason: "the output does not match how it was before the run".to_string(),
+            });
+        }
+        notice!("Verification passed");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{undo, UndoLog};
+    use crate::{utils::WriteJob, UndoArgs};
+
+    #[test]
+    fn test_undo_replays_in_reverse() {
+        let output = "test_undo_replays_in_reverse.bin";
+        let log = "test_undo_replays_in_reverse.ddsundo";
+        let before = vec![1u8; 4096];
+        std::fs::write(output, &before).unwrap();
+
+        // the same block is overwritten twice, undoing has to end with its first contents
+        let mut undo_log = UndoLog::create(log, 4096).unwrap();
+        undo_log.compared(&before);
+        let first = WriteJob::break_into_blocks(vec![2u8; 4096], &before, 4096, 0, 1024);
+        undo_log.record(&first, &before).unwrap();
+        let after_first = vec![2u8; 4096];
+        let second = WriteJob::break_into_blocks(vec![3u8; 1024], &after_first, 1024, 0, 1024);
+        undo_log.record(&second, &after_first).unwrap();
+        undo_log.finish().unwrap();
+        let mut overwritten = vec![2u8; 4096];
+        overwritten[..1024].fill(3);
+        std::fs::write(output, overwritten).unwrap();
+
+        let result = undo(&UndoArgs {
+            log: log.to_string(),
+            output: output.to_string(),
+            verify: true,
+        });
+        let after = std::fs::read(output).unwrap();
+        std::fs::remove_file(output).unwrap();
+        std::fs::remove_file(log).unwrap();
+
+        result.unwrap();
+        assert_eq!(after, before);
+    }
+}
@@ -1,11 +1,21 @@
 use std::{
     io::{Read, Seek, SeekFrom, Write},
-    ops::Range,
+    ops::{Deref, Range},
     path::Path,
-    process::exit,
 };
 
-use crate::Dds;
+use indicatif::{ProgressState, ProgressStyle};
+
+use crate::{
+    coalesce::Coalesce,
+    compare::dirty_chunks,
+    direct::DirectFile,
+    discard::{is_zero, Discarder},
+    error::DdsError,
+    mounts::check_not_mounted,
+    pool::PooledBuffer,
+    Dds,
+};
 
 #[derive(Debug)]
 struct Block {
@@ -15,112 +25,371 @@ struct Block {
     pub write_offset: u64,
 }
 
+/// The block of the input a `WriteJob` writes from.
+pub enum JobData {
+    Owned(Vec<u8>),
+    /// Handed back to its pool once the job has been written
+    Pooled(PooledBuffer),
+}
+
+impl Deref for JobData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            JobData::Owned(data) => data,
+            JobData::Pooled(data) => data,
+        }
+    }
+}
+
+impl std::fmt::Debug for JobData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "JobData({} bytes)", self.len())
+    }
+}
+
+impl From<Vec<u8>> for JobData {
+    fn from(data: Vec<u8>) -> JobData {
+        JobData::Owned(data)
+    }
+}
+
+impl From<PooledBuffer> for JobData {
+    fn from(data: PooledBuffer) -> JobData {
+        JobData::Pooled(data)
+    }
+}
+
+impl From<&[u8]> for JobData {
+    fn from(data: &[u8]) -> JobData {
+        JobData::Owned(data.to_vec())
+    }
+}
+
 #[derive(Debug)]
 pub struct WriteJob {
     pub offset: usize,
-    pub data: Vec<u8>,
+    /// The whole block of the input, the blocks to write are ranges of it
+    data: JobData,
     blocks: Vec<Block>,
 }
 
 impl WriteJob {
+    /// Find every `min_block_size` chunk of the first `limit` bytes of `input` that differs from
+    /// `invalid`, the block of the output at `offset`.
     pub fn break_into_blocks(
-        mut input: Vec<u8>,
+        input: impl Into<JobData>,
         invalid: &[u8],
-        mut limit: usize,
+        limit: usize,
         offset: usize,
         min_block_size: usize,
     ) -> WriteJob {
-        // loop through the input array in MIN_BLOCK_SIZE chunks, if the chunk is invalid, add it's indices into a block
-        // if the chunk is valid, drain it's data from the input array, and start reading the next block
-
-        let mut blocks = Vec::new();
-
-        let mut write_offset = 0;
-        let mut start = 0;
-        let mut end = min_block_size;
-        loop {
-            // if we've reached the end of the input, break
-            if end >= limit {
-                end = limit;
-            }
-            if start >= end {
-                break;
-            }
-
-            let step_size = end - start;
-
-            // if the block is invalid, add it to the blocks vector
-            if input[start..end] != invalid[(write_offset)..(write_offset + step_size)] {
-                blocks.push(Block {
-                    source: start..end,
-                    write_offset: (write_offset + offset) as u64,
-                });
-
-                // increment the offset and start/end indices
-                start += step_size;
-                end += step_size;
-            } else {
-                // if the block is valid, drain it from the input array
-                input.drain(start..end);
+        let data = input.into();
+        let dirty = dirty_chunks(&data, invalid, limit, min_block_size);
+        WriteJob::from_dirty(data, dirty, offset)
+    }
 
-                // decrease the limit by the size of the block
-                limit -= step_size;
-            }
+    /// Like `break_into_blocks`, but merges nearby dirty chunks into erase block aligned writes.
+    ///
+    /// The clean bytes between merged chunks are taken from the input, which matches the output
+    /// there anyway.
+    pub fn break_into_coalesced_blocks(
+        input: impl Into<JobData>,
+        invalid: &[u8],
+        limit: usize,
+        offset: usize,
+        min_block_size: usize,
+        coalesce: Coalesce,
+    ) -> WriteJob {
+        let data = input.into();
+        let dirty = dirty_chunks(&data, invalid, limit, min_block_size);
+        WriteJob::from_dirty(data, coalesce.merge(&dirty, limit), offset)
+    }
 
-            write_offset += step_size;
-        }
+    /// A job writing the given ranges of `data`, the block of the input at `offset`.
+    fn from_dirty(data: JobData, dirty: Vec<Range<usize>>, offset: usize) -> WriteJob {
+        let blocks = dirty
+            .into_iter()
+            .map(|range| Block {
+                write_offset: (offset + range.start) as u64,
+                source: range,
+            })
+            .collect();
 
         WriteJob {
             offset,
-            data: input,
+            data,
             blocks,
         }
     }
 
-    pub fn write<T: Seek + Read + Write>(self, file: &mut T) -> std::io::Result<usize> {
+    /// Compare the first `limit` bytes of a block of the input against the output, returning the
+    /// job that brings the output in line with the input, if they differ.
+    ///
+    /// The input is only taken into the job when it differs: a pooled buffer is moved in as it is,
+    /// a borrowed one is copied, so either way the caller's buffer can be reused.
+    pub fn diff<I>(
+        cfg: &Dds,
+        input: I,
+        output: &[u8],
+        limit: usize,
+        offset: usize,
+    ) -> Option<WriteJob>
+    where
+        I: Deref<Target = [u8]> + Into<JobData>,
+    {
+        // one pass finds both whether the block differs and where
+        let dirty = dirty_chunks(&input, output, limit, cfg.write_granularity);
+        if dirty.is_empty() {
+            return None;
+        }
+
+        let dirty = match cfg.coalesce() {
+            Some(coalesce) => coalesce.merge(&dirty, limit),
+            None => dirty,
+        };
+        Some(WriteJob::from_dirty(input.into(), dirty, offset))
+    }
+
+    pub fn write<T: Seek + Read + Write>(self, file: &mut T) -> Result<usize, DdsError> {
         let mut written = 0;
         for block in self.blocks.into_iter() {
-            let start_loc = file.stream_position()?;
+            let data_slice = &self.data[block.source];
+            let write_err = |source| DdsError::Write {
+                offset: block.write_offset,
+                len: data_slice.len(),
+                source,
+            };
+
+            let start_loc = file.stream_position().map_err(write_err)?;
 
             // seek and write data into file
-            let data_slice = &self.data[block.source];
-            file.seek(SeekFrom::Start(block.write_offset))?;
-            file.write_all(data_slice)?;
+            file.seek(SeekFrom::Start(block.write_offset))
+                .map_err(write_err)?;
+            file.write_all(data_slice).map_err(write_err)?;
             written += data_slice.len();
 
             // return cursor to original position
-            file.seek(SeekFrom::Start(start_loc))?;
+            file.seek(SeekFrom::Start(start_loc)).map_err(write_err)?;
         }
         Ok(written)
     }
 
+    /// Like `write`, but blocks of zeros are discarded from the output instead where it can.
+    pub fn write_discarding(
+        mut self,
+        file: &mut DirectFile,
+        discarder: &mut Discarder,
+    ) -> Result<usize, DdsError> {
+        // merge touching blocks, so a long run of zeros is discarded in one go
+        let mut runs: Vec<(Block, bool)> = Vec::with_capacity(self.blocks.len());
+        for block in std::mem::take(&mut self.blocks) {
+            let zero = is_zero(&self.data[block.source.clone()]);
+            match runs.last_mut() {
+                Some((last, last_zero))
+                    if *last_zero == zero
+                        && last.source.end == block.source.start
+                        && last.write_offset + last.source.len() as u64 == block.write_offset =>
+                {
+                    last.source.end = block.source.end;
+                }
+                _ => runs.push((block, zero)),
+            }
+        }
+
+        let mut discarded = 0;
+        for (block, zero) in runs {
+            let data = &self.data[block.source.clone()];
+            let done = zero
+                && discarder
+                    .discard(file.as_file(), block.write_offset, data)
+                    .map_err(|source| DdsError::Write {
+                        offset: block.write_offset,
+                        len: data.len(),
+                        source,
+                    })?;
+            match done {
+                true => discarded += data.len(),
+                false => self.blocks.push(block),
+            }
+        }
+
+        Ok(discarded + self.write(file)?)
+    }
+
+    /// The `(offset, length)` of every region of the output this job would overwrite.
+    pub fn regions(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.blocks
+            .iter()
+            .map(|block| (block.write_offset, block.source.len()))
+    }
+
+    /// The offset and data of every block this job would write to the output.
+    pub fn chunks(&self) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        self.blocks
+            .iter()
+            .map(|block| (block.write_offset, &self.data[block.source.clone()]))
+    }
+
+    /// The offset and current contents of every block this job would overwrite, taken from the
+    /// block of the output it was compared against.
+    pub fn originals<'a>(&'a self, output: &'a [u8]) -> impl Iterator<Item = (u64, &'a [u8])> + 'a {
+        self.regions().map(move |(offset, len)| {
+            let start = offset as usize - self.offset;
+            (offset, &output[start..start + len])
+        })
+    }
+
     pub fn len(&self) -> usize {
         self.blocks.len()
     }
 
+    /// How many bytes this job writes to the output.
+    pub fn bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.source.len()).sum()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.blocks.is_empty()
     }
 }
 
-pub fn validate_paths(cfg: &Dds) {
+/// Parse a size given on the command line, such as `512`, `4K`, `128KiB` or `1M`.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("{} is not a valid size", value))?;
+    let multiplier: usize = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("{} has an unknown size suffix {}", value, suffix)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{} is too large", value))
+}
+
+/// The style shared by every progress bar the controllers draw.
+pub fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-")
+}
+
+/// Like `progress_style`, led by the output the bar belongs to.
+pub fn output_progress_style() -> ProgressStyle {
+    ProgressStyle::with_template("{prefix:.bold} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-")
+}
+
+pub fn validate_paths(cfg: &Dds) -> Result<(), DdsError> {
     // check if the input file exists
     if !Path::new(&cfg.input).exists() {
-        eprintln!("Input file does not exist");
-        exit(1);
+        return Err(DdsError::MissingPath {
+            name: "Input",
+            path: cfg.input.clone(),
+        });
+    }
+
+    // check if the output file exists, a backup creates its image if needed
+    let creates_output = cfg.backup && !cfg.dry_run;
+    if !creates_output && !Path::new(cfg.output()).exists() {
+        return Err(DdsError::MissingPath {
+            name: "Output",
+            path: cfg.output().to_string(),
+        });
     }
 
-    // check if the output file exists
-    if !Path::new(&cfg.output).exists() {
-        eprintln!("Output file does not exist");
-        exit(1);
+    if cfg.writes_output() && !cfg.allow_mounted {
+        check_not_mounted(cfg.output())?;
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use super::{parse_size, WriteJob};
+    use crate::{coalesce::Coalesce, pool::BufferPool};
+
+    #[test]
+    fn test_break_into_coalesced_blocks() {
+        let input = vec![0u8; 1024 * 8];
+        let mut invalid = vec![0u8; 1024 * 8];
+        invalid[600] = 1;
+        invalid[1600] = 1;
+        invalid[1024 * 6] = 1;
+        let coalesce = Coalesce {
+            erase_block: 1024 * 4,
+            max_gap: 512,
+        };
+        let job = WriteJob::break_into_coalesced_blocks(
+            input.clone(),
+            &invalid,
+            1024 * 8,
+            1024 * 16,
+            512,
+            coalesce,
+        );
+
+        // the first two chunks are merged out to the start of the erase block
+        assert_eq!(
+            job.regions().collect::<Vec<_>>(),
+            vec![(1024 * 16, 2048), (1024 * 22, 512)]
+        );
+        assert_eq!(job.bytes(), 2048 + 512);
+
+        let mut c = Cursor::new(vec![0u8; 1024 * 24]);
+        c.get_mut()[1024 * 16..].copy_from_slice(&invalid);
+        let written = job.write(&mut c).unwrap();
+        assert_eq!(written, 2048 + 512);
+        assert_eq!(c.get_ref()[1024 * 16..], input[..]);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("128KiB"), Ok(128 * 1024));
+        assert_eq!(parse_size("1m"), Ok(1024 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("12X").is_err());
+    }
+
+    #[test]
+    fn test_pooled_job_returns_its_buffer() {
+        let pool = BufferPool::new(4096);
+        let mut input = pool.take();
+        input.fill(0);
+        input[100] = 1;
+        let job = WriteJob::break_into_blocks(input, &[0u8; 4096], 4096, 0, 512);
+        assert_eq!(pool.free(), 0);
+        let (offset, data) = job.chunks().next().unwrap();
+        assert_eq!((offset, data.len(), data[100]), (0, 512, 1));
+
+        let mut c = Cursor::new(vec![0u8; 4096]);
+        assert_eq!(job.write(&mut c).unwrap(), 512);
+        assert_eq!(c.get_ref()[100], 1);
+        assert_eq!(pool.free(), 1);
+    }
+
     #[test]
     fn test_break_into_blocks() {
         let input = vec![0u8; 1024 * 1024];
@@ -172,7 +441,7 @@ mod tests {
         let invalid = vec![0u8; 1024 * 1024];
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 1024, 0, 1024);
         assert_eq!(job.blocks.len(), 0);
-        assert_eq!(job.data.len(), 0);
+        assert_eq!(job.bytes(), 0);
 
         // Create a fake "file" with the invalid data
         let mut c = Cursor::new(invalid);
@@ -194,12 +463,11 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 3, 0, 1024);
 
         assert_eq!(job.blocks.len(), 1);
-        assert_eq!(job.data.len(), 1024);
+        assert_eq!(job.bytes(), 1024);
 
-        // make sure the adjusted block is correct
+        // the block points at its place in the input
         let block = job.blocks.first().unwrap();
-        assert_eq!(block.source.start, 0);
-        assert_eq!(block.source.end, 1024);
+        assert_eq!(block.source, 1024..2048);
         assert_eq!(block.write_offset, 1024);
 
         // Create a fake "file" with the invalid data
@@ -221,7 +489,7 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 3, 0, 1022);
 
         assert_eq!(job.blocks.len(), 4);
-        assert_eq!(job.data.len(), 1024 * 3);
+        assert_eq!(job.bytes(), 1024 * 3);
 
         // // make sure the adjusted block is correct
         let block = job.blocks.first().unwrap();
@@ -254,12 +522,11 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 3, 0, 1024);
 
         assert_eq!(job.blocks.len(), 1);
-        assert_eq!(job.data.len(), 1024);
+        assert_eq!(job.bytes(), 1024);
 
-        // // make sure the adjusted block is correct
+        // the block points at its place in the input
         let block = job.blocks.first().unwrap();
-        assert_eq!(block.source.start, 0);
-        assert_eq!(block.source.end, 1024);
+        assert_eq!(block.source, 0..1024);
         assert_eq!(block.write_offset, 0);
 
         // Create a fake "file" with the invalid data
@@ -282,12 +549,11 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 3, 0, 1024);
 
         assert_eq!(job.blocks.len(), 1);
-        assert_eq!(job.data.len(), 1024);
+        assert_eq!(job.bytes(), 1024);
 
-        // // make sure the adjusted block is correct
+        // the block points at its place in the input
         let block = job.blocks.first().unwrap();
-        assert_eq!(block.source.start, 0);
-        assert_eq!(block.source.end, 1024);
+        assert_eq!(block.source, 2048..3072);
         assert_eq!(block.write_offset, 1024 * 3 - 1024);
 
         // Create a fake "file" with the invalid data
@@ -313,7 +579,7 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input.clone(), &invalid, 1024 * 3, 0, 1024);
 
         assert_eq!(job.blocks.len(), 2);
-        assert_eq!(job.data.len(), 1024 * 2);
+        assert_eq!(job.bytes(), 1024 * 2);
 
         // make sure the adjusted block is correct
         let block = job.blocks.first().unwrap();
@@ -340,7 +606,7 @@ mod tests {
         let job = super::WriteJob::break_into_blocks(input, &invalid, 1024 * 3, 1024 * 10, 1024);
 
         assert_eq!(job.blocks.len(), 3);
-        assert_eq!(job.data.len(), 1024 * 3);
+        assert_eq!(job.bytes(), 1024 * 3);
 
         // make sure the adjusted block is correct
         let block = job.blocks.first().unwrap();
@@ -354,6 +620,20 @@ mod tests {
         assert_eq!(block.write_offset, 1024 * 12);
     }
 
+    #[test]
+    fn test_originals_come_from_the_output() {
+        let input = vec![1u8; 1024 * 3];
+        let mut output = input.clone();
+        output[100] = 2;
+        output[2048..2050].copy_from_slice(&[3, 4]);
+        let job = super::WriteJob::break_into_blocks(input, &output, 1024 * 3, 1024 * 10, 1024);
+
+        let originals: Vec<(u64, &[u8])> = job.originals(&output).collect();
+        assert_eq!(originals.len(), 2);
+        assert_eq!(originals[0], (1024 * 10, &output[..1024]));
+        assert_eq!(originals[1], (1024 * 12, &output[2048..]));
+    }
+
     #[test]
     fn test_break_into_blocks_real_test() {
         let input = [
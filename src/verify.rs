@@ -0,0 +1,143 @@
+use std::{fs::File, os::unix::io::AsRawFd};
+
+use indicatif::ProgressBar;
+
+use crate::{
+    compare::dirty_chunks,
+    direct::{AlignedBuffer, DirectFile},
+    error::DdsError,
+    events::{draw_target, Event},
+    input::progress_length,
+    input::Input,
+    notice,
+    reader::read_full,
+    report::DiffReport,
+    utils::progress_style,
+    Dds,
+};
+
+/// How much of each file is read at once while verifying.
+const VERIFY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Flush the output to the device and ask the kernel to drop its cached pages, so the verify pass
+/// reads back what is actually stored on the media rather than what we just wrote into memory.
+fn drop_page_cache(file: &File) {
+    if let Err(e) = file.sync_all() {
+        eprintln!("Unable to sync output before verifying: {}", e);
+    }
+
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    let res = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if res != 0 {
+        eprintln!(
+            "Unable to drop the page cache for the output, verifying against cached data: {}",
+            std::io::Error::from_raw_os_error(res)
+        );
+    }
+}
+
+/// Compare every byte of the output against the input, returning the regions that do not match.
+pub fn verify(cfg: &Dds) -> Result<DiffReport, DdsError> {
+    let pb = ProgressBar::with_draw_target(None, draw_target());
+    pb.set_style(progress_style());
+    verify_with(cfg, pb)
+}
+
+/// Like `verify`, showing progress on `pb`, which is left in the style it has.
+pub fn verify_with(cfg: &Dds, pb: ProgressBar) -> Result<DiffReport, DdsError> {
+    let mut i_file = Input::open(&cfg.input, cfg.direct)?;
+    let mut o_file = DirectFile::open(cfg.output(), false, cfg.direct)
+        .map_err(|e| DdsError::io("opening", cfg.output(), None, e))?;
+
+    drop_page_cache(o_file.as_file());
+
+    let i_file_size = progress_length(&i_file, o_file.as_file(), cfg.output())?;
+
+    pb.set_length(i_file_size);
+    pb.set_position(0);
+
+    let mut report = DiffReport::default();
+    let mut i_buffer = AlignedBuffer::new(VERIFY_BUFFER_SIZE);
+    let mut o_buffer = AlignedBuffer::new(VERIFY_BUFFER_SIZE);
+    let mut offset = 0u64;
+    loop {
+        let i_bytes_read = read_full(&mut i_file, &mut i_buffer)
+            .map_err(|e| DdsError::io("reading from", &cfg.input, Some(offset), e))?;
+        if i_bytes_read == 0 {
+            break;
+        }
+        let o_bytes_read = read_full(&mut o_file, &mut o_buffer[..i_bytes_read])
+            .map_err(|e| DdsError::io("reading from", cfg.output(), Some(offset), e))?;
+
+        for range in dirty_chunks(&i_buffer, &o_buffer, o_bytes_read, cfg.write_granularity) {
+            report.add(offset + range.start as u64..offset + range.end as u64);
+        }
+
+        // anything the output is missing counts as a mismatch, unless it was cut off on purpose
+        if o_bytes_read < i_bytes_read {
+            if cfg.allow_truncate {
+                break;
+            }
+            report.add(offset + o_bytes_read as u64..offset + i_bytes_read as u64);
+        }
+
+        offset += i_bytes_read as u64;
+        pb.set_position(offset);
+    }
+    pb.finish_with_message("Verified");
+
+    Ok(report)
+}
+
+/// Run the verify pass, failing if the output does not match the input.
+pub fn check(cfg: &Dds) -> Result<(), DdsError> {
+    notice!("Verifying {}", cfg.output());
+
+    let report = verify(cfg)?;
+    Event::Verify {
+        passed: report.is_empty(),
+        mismatched_bytes: report.bytes,
+    }
+    .emit();
+    if !report.is_empty() {
+        return Err(DdsError::VerifyFailed(report));
+    }
+
+    notice!("Verification passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use crate::Dds;
+
+    #[test]
+    fn test_verify_reports_mismatches() {
+        let input = "test_verify_reports_mismatches.bin";
+        let output = "test_verify_reports_mismatches.bin.copy";
+
+        let data = vec![7u8; 1024 * 1024 + 100];
+        let mut changed = data.clone();
+        changed[10] = 0;
+        changed[600_000] = 0;
+        changed.truncate(1024 * 1024);
+        std::fs::write(input, &data).unwrap();
+        std::fs::write(output, &changed).unwrap();
+
+        let cfg = Dds {
+            input: input.to_string(),
+            outputs: vec![output.to_string()],
+            ..Default::default()
+        };
+        let report = verify(&cfg).unwrap();
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+
+        assert_eq!(
+            report.ranges,
+            vec![0..512, 599_552..600_064, 1024 * 1024..1024 * 1024 + 100]
+        );
+    }
+}
@@ -10,6 +10,10 @@ use rand::{Rng, RngCore};
 const FILE_SIZE: usize = 1024 * 1024 * 1024;
 
 pub fn generate_test_file(filename: &str) {
+    generate_test_file_with_size(filename, FILE_SIZE);
+}
+
+pub fn generate_test_file_with_size(filename: &str, file_size: usize) {
     {
         println!("creating random file");
         // write the data to a file
@@ -18,7 +22,7 @@ pub fn generate_test_file(filename: &str) {
         let mut buf_writer = BufWriter::new(file);
         let mut rng = rand::thread_rng();
         let mut buffer = [0; 1024];
-        let mut remaining_size = FILE_SIZE;
+        let mut remaining_size = file_size;
 
         while remaining_size > 0 {
             let to_write = cmp::min(remaining_size, buffer.len());
@@ -49,15 +53,15 @@ pub fn generate_test_file(filename: &str) {
         let mut total_written = 0;
 
         for i in 0..50 {
-            let offset = rng.gen_range(0..FILE_SIZE - 1);
-            let size = rng.gen_range(1..(FILE_SIZE - offset).min(1024 * 5));
+            let offset = rng.gen_range(0..file_size - 1);
+            let size = rng.gen_range(1..(file_size - offset).min(1024 * 5));
             let mut data = vec![0u8; size];
             rng.fill_bytes(&mut data);
             file.write_at(&data, offset as u64).unwrap();
 
             println!(
                 "[{}]: mutated {} bytes at offset [{}/{}]",
-                i, size, offset, FILE_SIZE
+                i, size, offset, file_size
             );
             total_written += size;
         }
@@ -84,3 +88,19 @@ pub fn generate_test_file(filename: &str) {
 
     assert_eq!(file1_size, file2_size);
 }
+
+/// Save `data` as a gzip of many small members of random sizes. Decompressing it returns a short
+/// read at the end of every member, so the compare loop has to stitch whole blocks together.
+pub fn write_short_gzip_members(filename: &str, data: &[u8]) {
+    let mut rng = rand::thread_rng();
+    let mut file = BufWriter::new(std::fs::File::create(filename).unwrap());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (member, remaining) = rest.split_at(rng.gen_range(1..=rest.len().min(7000)));
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(member).unwrap();
+        file.write_all(&encoder.finish().unwrap()).unwrap();
+        rest = remaining;
+    }
+    file.flush().unwrap();
+}
@@ -0,0 +1,131 @@
+// only the small test files are used here
+#[allow(dead_code)]
+mod common;
+
+use assert_cmd::Command;
+use dds::{error::DdsError, multi::controller as multi_target_controller, Dds};
+
+use crate::common::generate_test_file_with_size;
+
+#[test]
+fn test_restore_every_output() {
+    let input = "test_restore_every_output.bin";
+    let first = "test_restore_every_output.bin.copy";
+    let second = "test_restore_every_output.bin.copy2";
+    let blank = "test_restore_every_output.bin.blank";
+    generate_test_file_with_size(input, 1024 * 1024);
+    std::fs::copy(first, second).unwrap();
+    std::fs::write(blank, vec![0u8; 1024 * 1024]).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![first.to_string(), second.to_string(), blank.to_string()],
+        verify: true,
+        ..Default::default()
+    };
+    let result = multi_target_controller(config);
+
+    let expected = std::fs::read(input).unwrap();
+    let restored: Vec<bool> = [first, second, blank]
+        .iter()
+        .map(|output| std::fs::read(output).unwrap() == expected)
+        .collect();
+    for path in [input, first, second, blank] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    result.unwrap();
+    assert_eq!(restored, vec![true, true, true]);
+}
+
+#[test]
+fn test_failed_output_does_not_stop_the_others() {
+    let input = "test_failed_output_does_not_stop_the_others.bin";
+    let good = "test_failed_output_does_not_stop_the_others.bin.copy";
+    let small = "test_failed_output_does_not_stop_the_others.bin.small";
+    let missing = "test_failed_output_does_not_stop_the_others.bin.missing";
+    generate_test_file_with_size(input, 1024 * 1024);
+    std::fs::write(small, vec![0u8; 1024]).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![small.to_string(), missing.to_string(), good.to_string()],
+        ..Default::default()
+    };
+    let result = multi_target_controller(config);
+
+    let good_restored = std::fs::read(good).unwrap() == std::fs::read(input).unwrap();
+    let small_after = std::fs::read(small).unwrap();
+    for path in [input, good, small] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(matches!(
+        result,
+        Err(DdsError::OutputsFailed {
+            failed: 2,
+            total: 3
+        })
+    ));
+    assert!(good_restored);
+    assert_eq!(small_after, vec![0u8; 1024]);
+}
+
+#[test]
+fn test_multi_target_cli() {
+    let input = "test_multi_target_cli.bin";
+    let first = "test_multi_target_cli.bin.copy";
+    let second = "test_multi_target_cli.bin.copy2";
+    generate_test_file_with_size(input, 1024 * 1024);
+    std::fs::copy(first, second).unwrap();
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(first)
+        .arg("--output")
+        .arg(second)
+        .arg("--yes")
+        .arg("--output-format")
+        .arg("json")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+
+    let expected = std::fs::read(input).unwrap();
+    let restored =
+        std::fs::read(first).unwrap() == expected && std::fs::read(second).unwrap() == expected;
+    for path in [input, first, second] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(restored);
+    assert!(stderr.contains("2 of 2 outputs succeeded"), "{}", stderr);
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect();
+    // each output runs from start to finish, with every event tagged with the output
+    for output in [first, second] {
+        let kinds: Vec<_> = events
+            .iter()
+            .filter(|event| event["output"] == output && event["event"] != "output")
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds.first(), Some(&"start"), "{}", stdout);
+        assert_eq!(kinds.last(), Some(&"finish"), "{}", stdout);
+        assert!(kinds.contains(&"write"), "{}", stdout);
+    }
+    // then an event for each output
+    let outputs: Vec<&serde_json::Value> = events
+        .iter()
+        .filter(|event| event["event"] == "output")
+        .collect();
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(outputs[1]["output"], second);
+    assert!(outputs[1]["error"].is_null());
+    assert_eq!(outputs[1]["summary"]["bytes_compared"], 1024 * 1024);
+}
@@ -1,10 +1,13 @@
 mod common;
 
-use crate::common::generate_test_file;
+use crate::common::{generate_test_file, generate_test_file_with_size, write_short_gzip_members};
 use assert_cmd::Command;
-use dds::{threaded::controller as multi_threaded_controller, Dds};
+use dds::{
+    checkpoint::Checkpointer, error::DdsError, lock::open_output, patch::apply,
+    threaded::controller as multi_threaded_controller, undo::undo, ApplyArgs, Dds, UndoArgs,
+};
 use sha2::{Digest, Sha256};
-use std::io::Read;
+use std::{io::Read, os::unix::fs::MetadataExt};
 
 #[test]
 fn test_large_file_duplicate() {
@@ -14,9 +17,9 @@ fn test_large_file_duplicate() {
 
     let config = Dds {
         input: "test_large_file_duplicate-multi.bin".to_string(),
-        output: "test_large_file_duplicate-multi.bin.copy".to_string(),
+        outputs: vec!["test_large_file_duplicate-multi.bin.copy".to_string()],
         threaded: true,
-        generate: None,
+        ..Default::default()
     };
 
     // run the controller
@@ -73,7 +76,7 @@ fn test_multithreading_cli() {
         .arg("--output")
         .arg("test_multithreading_cli.bin.copy")
         .arg("--threaded")
-        .write_stdin("y\n")
+        .arg("--yes")
         .assert()
         .success();
 
@@ -115,3 +118,466 @@ fn test_multithreading_cli() {
     std::fs::remove_file("test_multithreading_cli.bin").unwrap();
     std::fs::remove_file("test_multithreading_cli.bin.copy").unwrap();
 }
+
+#[test]
+fn test_dry_run_does_not_write_multi() {
+    let input = "test_dry_run-multi.bin";
+    let output = "test_dry_run-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let before = std::fs::read(output).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    // the output must be untouched
+    let after = std::fs::read(output).unwrap();
+    assert_eq!(before, after);
+    assert_ne!(std::fs::read(input).unwrap(), after);
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_resume_from_checkpoint_multi() {
+    let input = "test_resume_from_checkpoint-multi.bin";
+    let output = "test_resume_from_checkpoint-multi.bin.copy";
+    let checkpoint = "test_resume_from_checkpoint-multi.dds-checkpoint";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        resume: true,
+        checkpoint: Some(checkpoint.to_string()),
+        ..Default::default()
+    };
+
+    // pretend a previous run was interrupted part way through
+    let resume_at = 5120 * 100;
+    let o_file = std::fs::File::open(output).unwrap();
+    Checkpointer::new(&config, 0)
+        .unwrap()
+        .save(&o_file, resume_at as u64);
+    let before = std::fs::read(output).unwrap();
+
+    multi_threaded_controller(config).unwrap();
+
+    // everything before the checkpoint is skipped, everything after is restored
+    let input_data = std::fs::read(input).unwrap();
+    let after = std::fs::read(output).unwrap();
+    assert_eq!(before[..resume_at], after[..resume_at]);
+    assert_eq!(input_data[resume_at..], after[resume_at..]);
+    assert!(!std::path::Path::new(checkpoint).exists());
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_coalesced_writes_multi() {
+    let input = "test_coalesced_writes-multi.bin";
+    let output = "test_coalesced_writes-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024 + 100);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        read_block: 64 * 1024,
+        erase_block: Some(16 * 1024),
+        coalesce_gap: 8 * 1024,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_backup_creates_and_refreshes_image_multi() {
+    let device = "test_backup_creates_and_refreshes_image-multi.bin";
+    let image = "test_backup_creates_and_refreshes_image-multi.img";
+    generate_test_file_with_size(device, 1024 * 1024);
+    std::fs::remove_file(format!("{}.copy", device)).unwrap();
+
+    let config = || Dds {
+        input: device.to_string(),
+        outputs: vec![image.to_string()],
+        threaded: true,
+        backup: true,
+        ..Default::default()
+    };
+
+    // the first backup creates the image from scratch
+    multi_threaded_controller(config()).unwrap();
+    assert_eq!(
+        std::fs::read(device).unwrap(),
+        std::fs::read(image).unwrap()
+    );
+
+    // later backups refresh a stale image, even if its size has changed
+    let mut stale = std::fs::read(image).unwrap();
+    stale[1000] ^= 0xff;
+    stale.truncate(1000 * 1000);
+    std::fs::write(image, stale).unwrap();
+    multi_threaded_controller(config()).unwrap();
+    assert_eq!(
+        std::fs::read(device).unwrap(),
+        std::fs::read(image).unwrap()
+    );
+
+    std::fs::remove_file(device).unwrap();
+    std::fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn test_export_and_apply_patch_multi() {
+    let golden = "test_export_and_apply_patch-multi.bin";
+    let field = "test_export_and_apply_patch-multi.bin.copy";
+    let patch = "test_export_and_apply_patch-multi.ddspatch";
+    generate_test_file_with_size(golden, 1024 * 1024);
+    let field_before = std::fs::read(field).unwrap();
+
+    let config = Dds {
+        input: golden.to_string(),
+        outputs: vec![field.to_string()],
+        threaded: true,
+        export_patch: Some(patch.to_string()),
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    // exporting leaves the output alone, and the patch is much smaller than the image
+    assert_eq!(std::fs::read(field).unwrap(), field_before);
+    assert!(std::fs::metadata(patch).unwrap().len() < 1024 * 1024 / 2);
+
+    apply(&ApplyArgs {
+        patch: patch.to_string(),
+        output: field.to_string(),
+        verify: true,
+    })
+    .unwrap();
+    assert_eq!(
+        std::fs::read(golden).unwrap(),
+        std::fs::read(field).unwrap()
+    );
+
+    std::fs::remove_file(golden).unwrap();
+    std::fs::remove_file(field).unwrap();
+    std::fs::remove_file(patch).unwrap();
+}
+
+#[test]
+fn test_restore_from_compressed_input_multi() {
+    let image = "test_restore_from_compressed_input-multi.bin";
+    let compressed = "test_restore_from_compressed_input-multi.bin.zst";
+    let output = "test_restore_from_compressed_input-multi.bin.copy";
+    generate_test_file_with_size(image, 1024 * 1024);
+
+    let data = std::fs::read(image).unwrap();
+    std::fs::write(compressed, zstd::encode_all(&data[..], 1).unwrap()).unwrap();
+
+    let config = Dds {
+        input: compressed.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        verify: true,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), data);
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(compressed).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_discard_zeroed_regions_multi() {
+    let image = "test_discard_zeroed_regions-multi.img";
+    let output = "test_discard_zeroed_regions-multi.img.copy";
+
+    // the image zeroes the middle of the output
+    let mut data = vec![3u8; 4 * 1024 * 1024];
+    data[1024 * 1024..3 * 1024 * 1024].fill(0);
+    std::fs::write(image, &data).unwrap();
+    std::fs::write(output, vec![1u8; 4 * 1024 * 1024]).unwrap();
+    let blocks_before = std::fs::metadata(output).unwrap().blocks();
+
+    let config = Dds {
+        input: image.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        discard: true,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), data);
+    assert!(std::fs::metadata(output).unwrap().blocks() < blocks_before);
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_size_mismatch_multi() {
+    let input = "test_size_mismatch_multi.img";
+    let output = "test_size_mismatch_multi.img.copy";
+    std::fs::write(input, vec![1u8; 8192]).unwrap();
+    std::fs::write(output, vec![0u8; 4096]).unwrap();
+
+    let config = |allow_truncate| Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        allow_truncate,
+        ..Default::default()
+    };
+    let refused = multi_threaded_controller(config(false));
+    let refused_output = std::fs::read(output).unwrap();
+    multi_threaded_controller(config(true)).unwrap();
+    let truncated_output = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(matches!(
+        refused,
+        Err(DdsError::SizeMismatch {
+            input: 8192,
+            output: 4096
+        })
+    ));
+    assert_eq!(refused_output, vec![0u8; 4096]);
+    assert_eq!(truncated_output, vec![1u8; 4096]);
+}
+
+#[test]
+fn test_direct_io_multi() {
+    let input = "test_direct_io-multi.bin";
+    let output = "test_direct_io-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        direct: true,
+        verify: true,
+        read_block: 64 * 1024,
+        write_granularity: 4096,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_sync_every_multi() {
+    let input = "test_sync_every-multi.bin";
+    let output = "test_sync_every-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        sync_every: Some(64 * 1024),
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+    assert!(!std::path::Path::new(
+        "test_sync_every-multi.bin.test_sync_every-multi.bin.copy.dds-checkpoint"
+    )
+    .exists());
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_locked_output_is_refused_multi() {
+    let input = "test_locked_output-multi.bin";
+    let output = "test_locked_output-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+    let before = std::fs::read(output).unwrap();
+
+    let held = open_output(output, true, false).unwrap();
+    let result = multi_threaded_controller(Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        ..Default::default()
+    });
+    drop(held);
+    let after = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(matches!(result, Err(DdsError::Locked { .. })));
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_json_output_multi() {
+    let input = "test_json_output-multi.bin";
+    let output = "test_json_output-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .arg("--threaded")
+        .arg("--yes")
+        .arg("--output-format")
+        .arg("json")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.first().unwrap()["event"], "start");
+    assert_eq!(events.first().unwrap()["threaded"], true);
+    let summary = &events.last().unwrap()["summary"];
+    assert_eq!(summary["bytes_compared"], 1024 * 1024);
+    // the writes of the summary add up to the write events
+    let written: u64 = events
+        .iter()
+        .filter(|event| event["event"] == "write")
+        .map(|event| event["len"].as_u64().unwrap())
+        .sum();
+    assert_eq!(summary["bytes_written"], written);
+}
+
+#[test]
+fn test_stats_summary_multi() {
+    let input = "test_stats_summary-multi.bin";
+    let output = "test_stats_summary-multi.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .arg("--threaded")
+        .arg("--yes")
+        .arg("--output-format")
+        .arg("json")
+        .assert()
+        .success();
+    let output_text = assert.get_output();
+    let stdout = String::from_utf8_lossy(&output_text.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output_text.stderr).to_string();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    // the summary is printed for a person on stderr, and emitted for a program on stdout
+    assert!(stderr.contains("Compared 1.00 MiB in"));
+    assert!(stderr.contains("The writer waited"));
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    let summary = &summary["summary"];
+    assert!(summary["jobs"].as_u64().unwrap() > 0);
+    assert!(summary["percent_changed"].as_f64().unwrap() > 0.0);
+    assert!(summary["average_write_wait_secs"].as_f64().unwrap() > 0.0);
+}
+
+#[test]
+fn test_undo_log_multi() {
+    let input = "test_undo_log-multi.bin";
+    let output = "test_undo_log-multi.bin.copy";
+    let log = "test_undo_log-multi.ddsundo";
+    generate_test_file_with_size(input, 1024 * 1024);
+    let before = std::fs::read(output).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        undo_log: Some(log.to_string()),
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+    let restored = std::fs::read(output).unwrap();
+
+    let result = undo(&UndoArgs {
+        log: log.to_string(),
+        output: output.to_string(),
+        verify: true,
+    });
+    let undone = std::fs::read(output).unwrap();
+
+    assert_eq!(restored, std::fs::read(input).unwrap());
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+    std::fs::remove_file(log).unwrap();
+
+    result.unwrap();
+    assert_eq!(undone, before);
+}
+
+#[test]
+fn test_restore_with_short_reads_multi() {
+    let image = "test_restore_with_short_reads-multi.bin";
+    let compressed = "test_restore_with_short_reads-multi.bin.gz";
+    let output = "test_restore_with_short_reads-multi.bin.copy";
+    generate_test_file_with_size(image, 1024 * 1024);
+    let data = std::fs::read(image).unwrap();
+    write_short_gzip_members(compressed, &data);
+
+    let config = Dds {
+        input: compressed.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: true,
+        ..Default::default()
+    };
+    multi_threaded_controller(config).unwrap();
+    let restored = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(compressed).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(restored == data);
+}
@@ -1,12 +1,17 @@
 mod common;
 
+use std::os::unix::fs::MetadataExt;
+
 use std::io::Read;
 
 use assert_cmd::Command;
-use dds::{single::controller as single_threaded_controller, Dds};
+use dds::{
+    checkpoint::Checkpointer, error::DdsError, lock::open_output, patch::apply,
+    single::controller as single_threaded_controller, ApplyArgs, Dds, MIN_BLOCK_SIZE,
+};
 use sha2::{Digest, Sha256};
 
-use crate::common::generate_test_file;
+use crate::common::{generate_test_file, generate_test_file_with_size, write_short_gzip_members};
 
 #[test]
 fn test_large_file_duplicate_single() {
@@ -16,9 +21,9 @@ fn test_large_file_duplicate_single() {
 
     let config = Dds {
         input: "test_large_file_duplicate-single.bin".to_string(),
-        output: "test_large_file_duplicate-single.bin.copy".to_string(),
+        outputs: vec!["test_large_file_duplicate-single.bin.copy".to_string()],
         threaded: false,
-        generate: None,
+        ..Default::default()
     };
 
     // run the controller
@@ -70,7 +75,7 @@ fn test_singlethreaded_cli() {
         .arg("--output")
         .arg("test_single_cli.bin.copy")
         .arg("--threaded")
-        .write_stdin("y\n")
+        .arg("--yes")
         .assert()
         .success();
 
@@ -112,3 +117,669 @@ fn test_singlethreaded_cli() {
     std::fs::remove_file("test_single_cli.bin").unwrap();
     std::fs::remove_file("test_single_cli.bin.copy").unwrap();
 }
+
+#[test]
+fn test_dry_run_does_not_write_single() {
+    let input = "test_dry_run-single.bin";
+    let output = "test_dry_run-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let before = std::fs::read(output).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: false,
+        dry_run: true,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    // the output must be untouched
+    let after = std::fs::read(output).unwrap();
+    assert_eq!(before, after);
+    assert_ne!(std::fs::read(input).unwrap(), after);
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_verify_after_write_single() {
+    let input = "test_verify_after_write-single.bin";
+    let output = "test_verify_after_write-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        verify: true,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_missing_input_returns_error_single() {
+    let config = Dds {
+        input: "test_missing_input-single.bin".to_string(),
+        outputs: vec!["test_missing_input-single.bin.copy".to_string()],
+        ..Default::default()
+    };
+
+    let err = single_threaded_controller(config).unwrap_err();
+    assert!(matches!(err, DdsError::MissingPath { name: "Input", .. }));
+}
+
+#[test]
+fn test_resume_from_checkpoint_single() {
+    let input = "test_resume_from_checkpoint-single.bin";
+    let output = "test_resume_from_checkpoint-single.bin.copy";
+    let checkpoint = "test_resume_from_checkpoint-single.dds-checkpoint";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        resume: true,
+        checkpoint: Some(checkpoint.to_string()),
+        ..Default::default()
+    };
+
+    // pretend a previous run was interrupted part way through
+    let resume_at = 5120 * 100;
+    let o_file = std::fs::File::open(output).unwrap();
+    Checkpointer::new(&config, 0)
+        .unwrap()
+        .save(&o_file, resume_at as u64);
+    let before = std::fs::read(output).unwrap();
+
+    single_threaded_controller(config).unwrap();
+
+    // everything before the checkpoint is skipped, everything after is restored
+    let input_data = std::fs::read(input).unwrap();
+    let after = std::fs::read(output).unwrap();
+    assert_eq!(before[..resume_at], after[..resume_at]);
+    assert_ne!(input_data[..resume_at], after[..resume_at]);
+    assert_eq!(input_data[resume_at..], after[resume_at..]);
+    assert!(!std::path::Path::new(checkpoint).exists());
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_custom_block_sizes_single() {
+    let input = "test_custom_block_sizes-single.bin";
+    let output = "test_custom_block_sizes-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024 + 100);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        read_block: 64 * 1024,
+        write_granularity: 4096,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_backup_creates_and_refreshes_image_single() {
+    let device = "test_backup_creates_and_refreshes_image-single.bin";
+    let image = "test_backup_creates_and_refreshes_image-single.img";
+    generate_test_file_with_size(device, 1024 * 1024);
+    std::fs::remove_file(format!("{}.copy", device)).unwrap();
+
+    let config = || Dds {
+        input: device.to_string(),
+        outputs: vec![image.to_string()],
+        threaded: false,
+        backup: true,
+        ..Default::default()
+    };
+
+    // the first backup creates the image from scratch
+    single_threaded_controller(config()).unwrap();
+    assert_eq!(
+        std::fs::read(device).unwrap(),
+        std::fs::read(image).unwrap()
+    );
+
+    // later backups refresh a stale image, even if its size has changed
+    let mut stale = std::fs::read(image).unwrap();
+    stale[1000] ^= 0xff;
+    stale.truncate(1000 * 1000);
+    std::fs::write(image, stale).unwrap();
+    single_threaded_controller(config()).unwrap();
+    assert_eq!(
+        std::fs::read(device).unwrap(),
+        std::fs::read(image).unwrap()
+    );
+
+    std::fs::remove_file(device).unwrap();
+    std::fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn test_export_and_apply_patch_single() {
+    let golden = "test_export_and_apply_patch-single.bin";
+    let field = "test_export_and_apply_patch-single.bin.copy";
+    let patch = "test_export_and_apply_patch-single.ddspatch";
+    generate_test_file_with_size(golden, 1024 * 1024);
+    let field_before = std::fs::read(field).unwrap();
+
+    let config = Dds {
+        input: golden.to_string(),
+        outputs: vec![field.to_string()],
+        threaded: false,
+        export_patch: Some(patch.to_string()),
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    // exporting leaves the output alone, and the patch is much smaller than the image
+    assert_eq!(std::fs::read(field).unwrap(), field_before);
+    assert!(std::fs::metadata(patch).unwrap().len() < 1024 * 1024 / 2);
+
+    apply(&ApplyArgs {
+        patch: patch.to_string(),
+        output: field.to_string(),
+        verify: true,
+    })
+    .unwrap();
+    assert_eq!(
+        std::fs::read(golden).unwrap(),
+        std::fs::read(field).unwrap()
+    );
+
+    std::fs::remove_file(golden).unwrap();
+    std::fs::remove_file(field).unwrap();
+    std::fs::remove_file(patch).unwrap();
+}
+
+#[test]
+fn test_apply_truncated_patch_leaves_output_untouched() {
+    let golden = "test_apply_truncated_patch_leaves_output_untouched.bin";
+    let field = "test_apply_truncated_patch_leaves_output_untouched.bin.copy";
+    let patch = "test_apply_truncated_patch_leaves_output_untouched.ddspatch";
+    generate_test_file_with_size(golden, 1024 * 1024);
+    let field_before = std::fs::read(field).unwrap();
+
+    let config = Dds {
+        input: golden.to_string(),
+        outputs: vec![field.to_string()],
+        export_patch: Some(patch.to_string()),
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    // cut the patch off part way through its last record
+    let len = std::fs::metadata(patch).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(patch)
+        .unwrap()
+        .set_len(len - 100)
+        .unwrap();
+
+    let result = apply(&ApplyArgs {
+        patch: patch.to_string(),
+        output: field.to_string(),
+        verify: false,
+    });
+    let field_after = std::fs::read(field).unwrap();
+
+    std::fs::remove_file(golden).unwrap();
+    std::fs::remove_file(field).unwrap();
+    std::fs::remove_file(patch).unwrap();
+
+    assert!(matches!(result, Err(DdsError::Patch { .. })));
+    assert!(field_after == field_before);
+}
+
+#[test]
+fn test_apply_cli_rejects_corrupt_patch() {
+    let output = "test_apply_cli_rejects_corrupt_patch.bin";
+    let patch = "test_apply_cli_rejects_corrupt_patch.ddspatch";
+    std::fs::write(output, vec![1u8; 4096]).unwrap();
+    std::fs::write(patch, b"not a patch at all").unwrap();
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("apply")
+        .arg("--patch")
+        .arg(patch)
+        .arg("--output")
+        .arg(output)
+        .arg("--yes")
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+
+    std::fs::remove_file(output).unwrap();
+    std::fs::remove_file(patch).unwrap();
+
+    assert!(stderr.contains("not a dds patch"), "{}", stderr);
+}
+
+#[test]
+fn test_restore_from_compressed_input_single() {
+    let image = "test_restore_from_compressed_input-single.bin";
+    let compressed = "test_restore_from_compressed_input-single.bin.zst";
+    let output = "test_restore_from_compressed_input-single.bin.copy";
+    generate_test_file_with_size(image, 1024 * 1024);
+
+    let data = std::fs::read(image).unwrap();
+    std::fs::write(compressed, zstd::encode_all(&data[..], 1).unwrap()).unwrap();
+
+    let config = Dds {
+        input: compressed.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: false,
+        verify: true,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), data);
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(compressed).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_restore_from_sparse_input_single() {
+    let image = "test_restore_from_sparse_input_single.img";
+    let output = "test_restore_from_sparse_input_single.img.copy";
+
+    // a mostly empty image, restored over a device full of old data
+    let file = std::fs::File::create(image).unwrap();
+    file.set_len(4 * 1024 * 1024).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&file, &[7u8; 10_000], 1024 * 1024).unwrap();
+    drop(file);
+    std::fs::write(output, vec![1u8; 4 * 1024 * 1024]).unwrap();
+
+    let config = Dds {
+        input: image.to_string(),
+        outputs: vec![output.to_string()],
+        verify: true,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(image).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_discard_zeroed_regions_single() {
+    let image = "test_discard_zeroed_regions-single.img";
+    let output = "test_discard_zeroed_regions-single.img.copy";
+
+    // the image zeroes the middle of the output
+    let mut data = vec![3u8; 4 * 1024 * 1024];
+    data[1024 * 1024..3 * 1024 * 1024].fill(0);
+    std::fs::write(image, &data).unwrap();
+    std::fs::write(output, vec![1u8; 4 * 1024 * 1024]).unwrap();
+    let blocks_before = std::fs::metadata(output).unwrap().blocks();
+
+    let config = Dds {
+        input: image.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: false,
+        discard: true,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), data);
+    assert!(std::fs::metadata(output).unwrap().blocks() < blocks_before);
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_size_mismatch_policies_single() {
+    let input = "test_size_mismatch_policies_single.img";
+    let output = "test_size_mismatch_policies_single.img.copy";
+    let run = |input_data: &[u8], output_data: &[u8], config: Dds| {
+        std::fs::write(input, input_data).unwrap();
+        std::fs::write(output, output_data).unwrap();
+        let result = single_threaded_controller(Dds {
+            input: input.to_string(),
+            outputs: vec![output.to_string()],
+            ..config
+        });
+        (result, std::fs::read(output).unwrap())
+    };
+
+    // mismatched sizes are refused before anything is written
+    let (result, written) = run(&[1u8; 8192], &[0u8; 4096], Dds::default());
+    assert!(matches!(
+        result,
+        Err(DdsError::SizeMismatch {
+            input: 8192,
+            output: 4096
+        })
+    ));
+    assert_eq!(written, vec![0u8; 4096]);
+
+    let (result, written) = run(&[1u8; 4096], &[0u8; 8192], Dds::default());
+    assert!(matches!(
+        result,
+        Err(DdsError::SizeMismatch {
+            input: 4096,
+            output: 8192
+        })
+    ));
+    assert_eq!(written, vec![0u8; 8192]);
+
+    // unless told what to do with the difference
+    let allow_truncate = Dds {
+        allow_truncate: true,
+        verify: true,
+        ..Default::default()
+    };
+    let (result, written) = run(&[1u8; 8192], &[0u8; 4096], allow_truncate);
+    result.unwrap();
+    assert_eq!(written, vec![1u8; 4096]);
+
+    let zero_tail = Dds {
+        zero_tail: true,
+        ..Default::default()
+    };
+    let (result, written) = run(&[1u8; 4096], &[2u8; 8192], zero_tail);
+    result.unwrap();
+    assert_eq!(written, [vec![1u8; 4096], vec![0u8; 4096]].concat());
+
+    let ignore_tail = Dds {
+        ignore_tail: true,
+        ..Default::default()
+    };
+    let (result, written) = run(&[1u8; 4096], &[2u8; 8192], ignore_tail);
+    result.unwrap();
+    assert_eq!(written, [vec![1u8; 4096], vec![2u8; 4096]].concat());
+
+    // a compressed input can only be checked once it runs out
+    let compressed = zstd::encode_all(&[1u8; 8192][..], 1).unwrap();
+    let (result, _) = run(&compressed, &[0u8; 4096], Dds::default());
+    assert!(matches!(
+        result,
+        Err(DdsError::SizeMismatch {
+            input: 8192,
+            output: 4096
+        })
+    ));
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_direct_io_single() {
+    let input = "test_direct_io-single.bin";
+    let output = "test_direct_io-single.bin.copy";
+
+    // aligned writes go straight to the disk, smaller ones through the page cache
+    for (read_block, write_granularity) in [(64 * 1024, 4096), (64 * 1024, MIN_BLOCK_SIZE)] {
+        generate_test_file_with_size(input, 1024 * 1024);
+
+        let config = Dds {
+            input: input.to_string(),
+            outputs: vec![output.to_string()],
+            direct: true,
+            verify: true,
+            read_block,
+            write_granularity,
+            ..Default::default()
+        };
+        single_threaded_controller(config).unwrap();
+
+        assert_eq!(
+            std::fs::read(input).unwrap(),
+            std::fs::read(output).unwrap()
+        );
+    }
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_sync_every_single() {
+    let input = "test_sync_every-single.bin";
+    let output = "test_sync_every-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        sync_every: Some(64 * 1024),
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+
+    assert_eq!(
+        std::fs::read(input).unwrap(),
+        std::fs::read(output).unwrap()
+    );
+    // the checkpoints saved along the way are gone once the run is over
+    assert!(!std::path::Path::new(
+        "test_sync_every-single.bin.test_sync_every-single.bin.copy.dds-checkpoint"
+    )
+    .exists());
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_cli_refuses_without_terminal() {
+    let input = "test_cli_refuses_without_terminal.bin";
+    let output = "test_cli_refuses_without_terminal.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+    let before = std::fs::read(output).unwrap();
+
+    // piping in a yes isn't enough, it has to be given on the command line
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .write_stdin("y\n")
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    let after = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(stderr.contains("pass --yes"), "{}", stderr);
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_locked_output_is_refused_single() {
+    let input = "test_locked_output-single.bin";
+    let output = "test_locked_output-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+    let before = std::fs::read(output).unwrap();
+
+    // another run is already writing to the output
+    let held = open_output(output, true, false).unwrap();
+    let result = single_threaded_controller(Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        ..Default::default()
+    });
+    drop(held);
+    let after = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(matches!(result, Err(DdsError::Locked { .. })));
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_json_output_single() {
+    let input = "test_json_output-single.bin";
+    let output = "test_json_output-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .arg("--verify")
+        .arg("--yes")
+        .arg("--output-format")
+        .arg("json")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    let matches = std::fs::read(input).unwrap() == std::fs::read(output).unwrap();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    // every line of stdout is an event
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds.first(), Some(&"start"));
+    assert!(kinds.contains(&"write"));
+    assert!(kinds.contains(&"verify"));
+    assert_eq!(kinds.last(), Some(&"finish"));
+
+    let summary = &events.last().unwrap()["summary"];
+    assert_eq!(summary["bytes_compared"], 1024 * 1024);
+    assert!(summary["bytes_written"].as_u64().unwrap() > 0);
+    assert!(summary["blocks"].as_u64().unwrap() > 0);
+    assert!(matches);
+}
+
+#[test]
+fn test_stats_summary_single() {
+    let input = "test_stats_summary-single.bin";
+    let output = "test_stats_summary-single.bin.copy";
+    generate_test_file_with_size(input, 1024 * 1024);
+
+    let assert = Command::cargo_bin("dds")
+        .unwrap()
+        .arg("--input")
+        .arg(input)
+        .arg("--output")
+        .arg(output)
+        .arg("--yes")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(stdout.contains("Compared 1.00 MiB in"));
+    assert!(stdout.contains("% changed"));
+    assert!(stdout.contains(" jobs of "));
+    assert!(stdout.contains("reading ("));
+    assert!(stdout.contains("writing ("));
+}
+
+#[test]
+fn test_undo_log_single() {
+    let input = "test_undo_log-single.bin";
+    let output = "test_undo_log-single.bin.copy";
+    let log = "test_undo_log-single.ddsundo";
+    generate_test_file_with_size(input, 1024 * 1024);
+    let before = std::fs::read(output).unwrap();
+
+    let config = Dds {
+        input: input.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: false,
+        undo_log: Some(log.to_string()),
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+    let restored = std::fs::read(output).unwrap();
+
+    Command::cargo_bin("dds")
+        .unwrap()
+        .arg("undo")
+        .arg("--log")
+        .arg(log)
+        .arg("--output")
+        .arg(output)
+        .arg("--verify")
+        .arg("--yes")
+        .assert()
+        .success();
+    let undone = std::fs::read(output).unwrap();
+
+    assert_eq!(restored, std::fs::read(input).unwrap());
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(output).unwrap();
+    std::fs::remove_file(log).unwrap();
+
+    assert_eq!(undone, before);
+}
+
+#[test]
+fn test_restore_with_short_reads_single() {
+    let image = "test_restore_with_short_reads-single.bin";
+    let compressed = "test_restore_with_short_reads-single.bin.gz";
+    let output = "test_restore_with_short_reads-single.bin.copy";
+    generate_test_file_with_size(image, 1024 * 1024);
+    let data = std::fs::read(image).unwrap();
+    write_short_gzip_members(compressed, &data);
+
+    let config = Dds {
+        input: compressed.to_string(),
+        outputs: vec![output.to_string()],
+        threaded: false,
+        ..Default::default()
+    };
+    single_threaded_controller(config).unwrap();
+    let restored = std::fs::read(output).unwrap();
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(compressed).unwrap();
+    std::fs::remove_file(output).unwrap();
+
+    assert!(restored == data);
+}